@@ -5,18 +5,28 @@ extern crate rustbox;
 extern crate rand;
 extern crate unicode_segmentation;
 extern crate getopts;
+extern crate libc;
 
 use getopts::Options;
 use std::env;
 
-use std::cmp::min;
+use std::cmp::{max, min};
+use std::collections::{HashMap, HashSet};
 use std::default::Default;
 use std::fs;
 use std::fs::File;
 use std::io;
-use std::io::{Read, Write};
-use std::path::Path;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::process;
 use std::process::exit;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 use rand::Rng;
 use unicode_segmentation::UnicodeSegmentation;
@@ -35,18 +45,151 @@ struct State {
     adding: String,
     mode: Mode,
     status: Status,
-    correct_pass: String,
+    // What has to be typed to save, see gate().
+    challenge: Puzzle,
     pass_input: String,
+    // Only used to show what entries without their own sink resolve to.
+    default_sink: String,
+    // Highlighted row of the group view.
+    group_selected: usize,
+    group_settings: Vec<GroupSettings>,
+    friction: Friction,
+    // The passphrase screen waiting behind the unblock gate, see gate().
+    gate: Mode,
+    gate_opened: u64,
+    // The first reason typed, the rest have to match it.
+    reason: String,
+    reasons_left: usize,
+    // Passphrases typed this session that made it through the cooldown, each
+    // counts as an unblock.
+    passphrases: usize,
+    // Seconds between the passphrase and the unblock, and when the running
+    // countdown ends.
+    cooldown: u64,
+    cooldown_until: u64,
+    // How long the temporary unblock waiting out its cooldown is for.
+    unlock_for: u64,
+    // The domains and group settings as they were read, to tell what this
+    // session unblocked.
+    loaded: Vec<Domain>,
+    loaded_groups: Vec<GroupSettings>,
+    // Passphrase words before friction, more for whole groups.
+    pass_words: usize,
+    challenges: ChallengeConfig,
+    // Pasted challenges don't count, see the Anti-paste section.
+    paste_check: bool,
+    last_key_ms: u64,
+    fast_keys: usize,
+    pasted: bool,
+    // Temporary unblocks made this session, journaled once they're saved.
+    journal: Vec<JournalEntry>,
+    // Edits that can be undone, and undone ones that can be redone, most
+    // recent last.
+    undo: Vec<Snapshot>,
+    redo: Vec<Snapshot>,
+    // Only domains matching this are shown, see matches().
+    filter: String,
+    // Terminal rows, and the first shown row of the list (counting only what
+    // the filter lets through).
+    height: usize,
+    scroll: usize,
+    // Domains picked with the mark key, and where visual mode's range
+    // started, see targets().
+    marked: Vec<String>,
+    anchor: usize,
 }
 
-
+// The list as it was before an edit, see remember().
 #[derive(Clone)]
+struct Snapshot {
+    // What the edit was, e.g. "delete example.com".
+    what: String,
+    domains: Vec<Domain>,
+    group_settings: Vec<GroupSettings>,
+    selected: usize,
+    status: Status,
+}
+
+
+#[derive(Clone, PartialEq)]
 struct Domain {
     url: String,
     status: DomainStatus,
+    // A blocked domain gets one line per address family so lookups for AAAA
+    // records are caught as well, parse_hosts folds the pair back together.
+    ip: Option<String>,
+    ip6: Option<String>,
+    // Where this domain should resolve to instead of the global sink, e.g. a
+    // local "get back to work" page.
+    sink: Option<String>,
+    group: Option<String>,
+    // When the domain should be blocked, see parse_schedule.  Overrides the
+    // group's schedule.
+    schedule: Option<String>,
+    // A temporary unblock: when (unix time) the domain gets blocked again.
+    until: Option<u64>,
+    aliases: Vec<String>,
+    // What's left of the entry's trailing comment once hostblock's own
+    // "key:value" words are taken out.
+    comment: Option<String>,
+    // Comments, blank and unparseable lines directly above the entry, they
+    // travel with it so nothing in the section is lost on save.
+    leading: Vec<String>,
+    // The line(s) as they were read, written back as-is while the entry is
+    // unchanged so an untouched file stays byte-identical.
+    raw: Option<String>,
+}
+
+// What the next unblock costs, more the more often hostblock was unblocked
+// lately, see friction_for.
+#[derive(Clone)]
+struct Friction {
+    // On top of the usual two words for a domain and six for a group.
+    extra_words: usize,
+    // Seconds before the passphrase can be typed.
+    wait: u64,
+    // How many times the same reason has to be typed, 0 for none.
+    reasons: usize,
+}
+
+// Settings that belong to a whole group rather than one entry, kept on a
+// "## group <name> key:value ..." line at the top of the managed section.
+#[derive(Clone, PartialEq)]
+struct GroupSettings {
+    name: String,
+    schedule: Option<String>,
+    // The kind of challenge for unblocking the group, see challenge_for.
+    challenge: Option<String>,
+    raw: Option<String>,
 }
 
+// One generated challenge: `prompt` is shown under `title`, and typing
+// `answer` passes it.
 #[derive(Clone)]
+struct Puzzle {
+    title: String,
+    prompt: String,
+    answer: String,
+}
+
+// The configured challenge kind for groups without their own, and where the
+// words come from.
+#[derive(Clone)]
+struct ChallengeConfig {
+    default: String,
+    wordlist: Option<PathBuf>,
+}
+
+// One blocking window of a schedule, in minutes since midnight.  A window
+// that ends before it starts runs past midnight into the next day.
+struct Window {
+    // Monday first
+    days: [bool; 7],
+    start: u32,
+    end: u32,
+}
+
+#[derive(Clone, PartialEq)]
 enum DomainStatus {
     Blocked,
     Unblocked,
@@ -63,6 +206,10 @@ enum Movement {
     Bottom,
     Up,
     Down,
+    PageUp,
+    PageDown,
+    HalfPageUp,
+    HalfPageDown,
 }
 
 #[derive(Clone)]
@@ -71,8 +218,184 @@ enum Mode {
     Normal,
     Password,
     Help,
+    Sink,
+    Groups,
+    // Typing the group for the selected domain.
+    Group,
+    // Typing the passphrase for a temporary unblock, then how long it's for.
+    Unlock,
+    Duration,
+    // Saying why, before the passphrase when unblocking a lot.
+    Reason,
+    // Typing the filter for the list.
+    Search,
+    // Picking a range of domains to act on at once.
+    Visual,
+    // Counting down after the passphrase, the unblock only happens at zero.
+    Cooldown,
+}
+
+// Where hostblock reads and writes, settable with --hosts-file or
+// HOSTBLOCK_HOSTS_FILE so it can be pointed at a scratch file or a
+// container's hosts file instead of the real one.
+#[derive(Clone)]
+struct Config {
+    hosts_path: PathBuf,
+    backup_dir: PathBuf,
+    keep_backups: usize,
+    sink: String,
+    // None turns the IPv6 lines off.
+    sink6: Option<String>,
+    // What hostblock remembers between runs, e.g. the section it last wrote.
+    state_dir: PathBuf,
+    // Where `hostblock serve` puts its sockets, and the group allowed to
+    // unblock through them.
+    socket_dir: PathBuf,
+    unblock_group: Option<String>,
+    cooldown: u64,
+    challenges: ChallengeConfig,
+    // Off for test harnesses that type the passphrase for you.
+    paste_check: bool,
+}
+
+impl Config {
+    // Backups are named <hosts file name>.hb.back.<unix time>, the
+    // timestamp doubles as the id used by `backups restore`.
+    fn backup_prefix(&self) -> String {
+        let name = match self.hosts_path.file_name() {
+            Some(name) => name.to_string_lossy().into_owned(),
+            None => String::from("hosts"),
+        };
+        name + ".hb.back."
+    }
+
+    fn backup_path(&self, id: u64) -> PathBuf {
+        self.backup_dir.join(format!("{}{}", self.backup_prefix(), id))
+    }
+
+    // The managed section as hostblock last wrote it, what the daemon puts
+    // back when someone edits it by hand.  Named after the whole hosts path
+    // so scratch files don't clash with /etc/hosts.
+    fn section_path(&self) -> PathBuf {
+        let name = self.hosts_path.to_string_lossy().trim_matches('/').replace('/', "_");
+        self.state_dir.join(format!("{}.section", name))
+    }
+}
+
+struct Backup {
+    id: u64,
+    path: PathBuf,
 }
 
+#[derive(Clone, Copy)]
+enum Format {
+    Plain,
+    Tsv,
+    Json,
+}
+
+impl Format {
+    fn parse(s: &str) -> Option<Format> {
+        match s {
+            "plain" => Some(Format::Plain),
+            "tsv" => Some(Format::Tsv),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum ExportFormat {
+    Plain,
+    Hosts,
+    Dnsmasq,
+    Unbound,
+    Adblock,
+}
+
+impl ExportFormat {
+    fn parse(s: &str) -> Option<ExportFormat> {
+        match s {
+            "plain" => Some(ExportFormat::Plain),
+            "hosts" => Some(ExportFormat::Hosts),
+            "dnsmasq" => Some(ExportFormat::Dnsmasq),
+            "unbound" => Some(ExportFormat::Unbound),
+            "adblock" => Some(ExportFormat::Adblock),
+            _ => None,
+        }
+    }
+}
+
+// Exit codes of `hostblock status`, so prompts and status bars don't have to
+// parse the output.
+static STATUS_ALL_BLOCKED: i32 = 0;
+static STATUS_SOME_UNBLOCKED: i32 = 3;
+static STATUS_NONE_BLOCKED: i32 = 4;
+
+// A hosts file split around the managed section.  Everything outside of the
+// section is kept verbatim.
+#[derive(Clone)]
+struct HostsFile {
+    before: Vec<String>,
+    // The "### HostBlock" line, None if the file has no section yet.
+    header: Option<String>,
+    domains: Vec<Domain>,
+    // Comments and blank lines after the last entry.
+    trailing: Vec<String>,
+    footer: Option<String>,
+    after: Vec<String>,
+    ends_with_newline: bool,
+    warnings: Vec<String>,
+    groups: Vec<GroupSettings>,
+}
+
+// Hostblock keeps what it knows about an entry in the entry's trailing comment
+// as "key:value" words, e.g. "# group:social", next to any note already there.
+static META_KEYS: &'static [&'static str] = &["group", "schedule", "until", "challenge", "sink"];
+static GROUP_LINE: &'static str = "## group ";
+
+static SECTION_START: &'static str = "### HostBlock";
+static SECTION_END: &'static str = "### End HostBlock";
+static DEFAULT_SINK: &'static str = "127.0.0.1";
+static DEFAULT_SINK6: &'static str = "::1";
+static SINK_VAR: &'static str = "HOSTBLOCK_SINK";
+static SINK6_VAR: &'static str = "HOSTBLOCK_SINK6";
+
+static DEFAULT_CONFIG_PATH: &'static str = "/etc/hostblock.conf";
+static CONFIG_PATH_VAR: &'static str = "HOSTBLOCK_CONFIG";
+
+static DEFAULT_HOSTS_PATH: &'static str = "/etc/hosts";
+static HOSTS_PATH_VAR: &'static str = "HOSTBLOCK_HOSTS_FILE";
+// A hosts file written from scratch, everyone needs to read it.
+static HOSTS_MODE: u32 = 0o644;
+static BACKUP_DIR_VAR: &'static str = "HOSTBLOCK_BACKUP_DIR";
+static KEEP_BACKUPS_VAR: &'static str = "HOSTBLOCK_KEEP_BACKUPS";
+static DEFAULT_STATE_DIR: &'static str = "/var/lib/hostblock";
+static STATE_DIR_VAR: &'static str = "HOSTBLOCK_STATE_DIR";
+static DEFAULT_SOCKET_DIR: &'static str = "/run/hostblock";
+static SOCKET_DIR_VAR: &'static str = "HOSTBLOCK_SOCKET_DIR";
+static UNBLOCK_GROUP_VAR: &'static str = "HOSTBLOCK_UNBLOCK_GROUP";
+static COOLDOWN_VAR: &'static str = "HOSTBLOCK_COOLDOWN";
+static CHALLENGE_VAR: &'static str = "HOSTBLOCK_CHALLENGE";
+static WORDLIST_VAR: &'static str = "HOSTBLOCK_WORDLIST";
+static PASTE_CHECK_VAR: &'static str = "HOSTBLOCK_PASTE_CHECK";
+static CHALLENGE_KINDS: &'static [&'static str] = &["words", "string", "arithmetic", "paragraph"];
+static DEFAULT_COOLDOWN: u64 = 60;
+// Anyone may read, add and block through the control socket, unblocking
+// takes the unblock socket, which only root and --unblock-group can open.
+static CONTROL_SOCKET: &'static str = "control.sock";
+static UNBLOCK_SOCKET: &'static str = "unblock.sock";
+// Anyone can connect, so nobody gets to tie the server up: there's a limit
+// on clients and on request length, and a subscriber that stops reading is
+// dropped instead of blocking everyone else's events.
+static MAX_CLIENTS: usize = 64;
+static MAX_REQUEST: usize = 64 * 1024;
+static SUBSCRIBER_TIMEOUT_MS: u64 = 1000;
+static DEFAULT_KEEP_BACKUPS: usize = 10;
+// Edits kept for undo.
+static HISTORY: usize = 100;
+
 // taken straight from termui
 static TOP_RIGHT: &'static str = "┐";
 static VERTICAL_LINE: &'static str = "│";
@@ -81,69 +404,112 @@ static TOP_LEFT: &'static str = "┌";
 static BOTTOM_RIGHT: &'static str = "┘";
 static BOTTOM_LEFT: &'static str = "└";
 
-static BOX_WIDTH: usize = 55;
+// Narrower than this the domain column gives up room to nothing else.
+static MIN_DOMAIN_WIDTH: usize = 12;
 
 fn main() {
-    match fs::copy(Path::new("/etc/hosts"), Path::new("/etc/hosts.hb.back")) {
-        Ok(_) => (),
-        Err(_) => {
-            writeln!(&mut std::io::stderr(), "Couldn't access /etc/hosts.  Try running with sudo.")
-                .unwrap();
-            exit(1);
-        }
-    }
-
-    let (show_menu, mut state) = read_args();
+    let (show_menu, config, mut state) = read_args();
 
 
     if show_menu{
+        // Declared first so it's dropped after rustbox has left the screen.
+        let _paste = BracketedPaste::enable(state.paste_check);
         let rustbox = RustBox::init(Default::default()).unwrap();
+        state = resize(&state, rustbox.height());
         rustbox.draw(&state);
 
+        // Wakes up every so often to keep the countdowns running.
         loop {
-            if let rustbox::Event::KeyEvent(mkey) = rustbox.poll_event(false)
-                                                           .ok()
-                                                           .expect("poll failed") {
-                match mkey {
-                    Key::Ctrl('c') => break,
-                    k => {
-                        let (quit, new_state) = handle_key(k, &state);
-                        if quit {
-                            break
-                        }
-                        state = new_state;
-                        rustbox.draw(&state);
+            let (quit, new_state) = match rustbox.peek_event(Duration::from_millis(250), false)
+                                                 .ok()
+                                                 .expect("poll failed") {
+                rustbox::Event::KeyEvent(Key::Ctrl('c')) => {
+                    // Not a way around the passphrase.
+                    if let Status::Dirty = state.status {
+                        drop(rustbox);
+                        println!("Nothing saved.");
+                        return;
+                    }
+                    break;
+                }
+                rustbox::Event::KeyEvent(Key::Esc) if typing_challenge(&state) => {
+                    if read_paste(&rustbox) {
+                        (false, reject_paste(&state))
+                    } else {
+                        handle_key(Key::Esc, &state)
                     }
-                    //_ => {}
                 }
+                rustbox::Event::KeyEvent(k) => handle_key(k, &state),
+                rustbox::Event::ResizeEvent(_, height) => (false, resize(&state, height as usize)),
+                _ => tick(&state),
+            };
+            if quit {
+                break
             }
+            state = new_state;
+            rustbox.draw(&state);
         }
     } // force rustbox out of scope to clear window, so that error messages
       // can be seen when saving.
 
-    match save_hosts(&state) {
-        Ok(_) => {}
-        Err(e) => panic!(e),
+    match save_hosts(&config, &state) {
+        Ok(_) => {
+            if state.passphrases > 0 {
+                if let Err(e) = record_unblocks(&config, state.passphrases) {
+                    writeln!(&mut std::io::stderr(), "warning: couldn't record the unblock: {}", e)
+                        .unwrap();
+                }
+            }
+            // The temporary unblocks are already in, everything else was
+            // unblocked behind the last reason typed.
+            let mut entries = state.journal.clone();
+            for entry in unblocked_entries(&state, &state.reason, now()) {
+                if !entries.iter().any(|e| e.domain == entry.domain) {
+                    entries.push(entry);
+                }
+            }
+            if let Err(e) = record_journal(&config, &entries) {
+                writeln!(&mut std::io::stderr(), "warning: couldn't write the journal: {}", e)
+                    .unwrap();
+            }
+        }
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't save {}, it has been left untouched: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            exit(1);
+        }
     };
 }
 // true, the args allowed us to bypass the GUI
 // false, nevermind show the GUI
-fn read_args() -> (bool, State){
+fn read_args() -> (bool, Config, State){
     fn print_usage(program: &str, opts: Options) {
-        let brief = format!("Usage: {} [options]", program);
+        let brief = format!("Usage: {0} [options]\n       \
+                             {0} [options] add|remove|block|unblock <domain>...\n       \
+                             {0} [options] block|unblock --group <group>\n       \
+                             {0} [options] group <group>|- <domain>...\n       \
+                             {0} [options] groups\n       \
+                             {0} [options] journal\n       \
+                             {0} [options] schedule <spec>|- <domain>...\n       \
+                             {0} [options] schedule --group <group> <spec>|-\n       \
+                             {0} [options] challenge --group <group> <kind>|-\n       \
+                             {0} [options] apply\n       \
+                             {0} [options] unblock --for <minutes> <domain>...\n       \
+                             {0} [options] expire\n       \
+                             {0} [options] daemon\n       \
+                             {0} [options] serve\n       \
+                             {0} [options] list|status [--format plain|tsv|json]\n       \
+                             {0} [options] import <file>|-\n       \
+                             {0} [options] export [--format plain|hosts|dnsmasq|unbound|adblock]\n       \
+                             {0} [options] backups list\n       \
+                             {0} [options] backups restore <id>",
+                            program);
         print!("{}", opts.usage(&brief));
     }
 
-    let state = State {
-        selected: 0,
-        domains: parse_hosts(read_hosts()),
-        adding: String::from(""),
-        pass_input: String::from(""),
-        correct_pass: gen_pass(2),
-        status: Status::Clean,
-        mode: Mode::Normal,
-    };
-
     let args: Vec<String> = env::args().collect();
     let program = args[0].clone();
 
@@ -151,42 +517,534 @@ fn read_args() -> (bool, State){
     opts.optflag("u", "unblock", "unblock all hosts (requires passphrase)");
     opts.optflag("b", "block", "block all hosts");
     opts.optflag("h", "help", "print this help menu");
+    opts.optopt("c",
+                "config",
+                &format!("config file (default {}, or ${})",
+                         DEFAULT_CONFIG_PATH,
+                         CONFIG_PATH_VAR),
+                "PATH");
+    opts.optopt("f",
+                "hosts-file",
+                &format!("hosts file to manage (default {}, or ${})",
+                         DEFAULT_HOSTS_PATH,
+                         HOSTS_PATH_VAR),
+                "PATH");
+    opts.optopt("",
+                "backup-dir",
+                &format!("where to keep backups (default next to the hosts file, or ${})",
+                         BACKUP_DIR_VAR),
+                "DIR");
+    opts.optopt("",
+                "keep-backups",
+                &format!("number of backups to keep (default {}, or ${})",
+                         DEFAULT_KEEP_BACKUPS,
+                         KEEP_BACKUPS_VAR),
+                "N");
+    opts.optopt("",
+                "sink",
+                &format!("IPv4 address blocked domains resolve to (default {}, or ${})",
+                         DEFAULT_SINK,
+                         SINK_VAR),
+                "ADDR");
+    opts.optopt("",
+                "sink6",
+                &format!("IPv6 address blocked domains resolve to, or \"none\" to only \
+                          block IPv4 (default {}, or ${})",
+                         DEFAULT_SINK6,
+                         SINK6_VAR),
+                "ADDR");
+    opts.optopt("",
+                "state-dir",
+                &format!("where hostblock keeps its own state (default {}, or ${})",
+                         DEFAULT_STATE_DIR,
+                         STATE_DIR_VAR),
+                "DIR");
+    opts.optopt("",
+                "socket-dir",
+                &format!("where serve puts its sockets (default {}, or ${})",
+                         DEFAULT_SOCKET_DIR,
+                         SOCKET_DIR_VAR),
+                "DIR");
+    opts.optopt("",
+                "unblock-group",
+                &format!("group allowed to unblock through serve's unblock socket (default \
+                          only root, or ${})",
+                         UNBLOCK_GROUP_VAR),
+                "GROUP");
+    opts.optopt("",
+                "challenge",
+                &format!("what unblocking takes: words, string, arithmetic or paragraph \
+                          (default words, or ${})",
+                         CHALLENGE_VAR),
+                "KIND");
+    opts.optopt("",
+                "wordlist",
+                &format!("file with one word per line for the words challenge (or ${})",
+                         WORDLIST_VAR),
+                "PATH");
+    opts.optopt("",
+                "paste-check",
+                &format!("on or off: reject passphrases that are pasted or typed faster \
+                          than a person can (default on, or ${})",
+                         PASTE_CHECK_VAR),
+                "ON|OFF");
+    opts.optopt("",
+                "cooldown",
+                &format!("seconds between typing the passphrase and the unblock (default {}, \
+                          or ${})",
+                         DEFAULT_COOLDOWN,
+                         COOLDOWN_VAR),
+                "SECONDS");
+    opts.optflag("y", "yes", "don't ask for confirmation when restoring a backup");
+    opts.optopt("",
+                "group",
+                "group to add domains to, or to block/unblock/schedule as a whole",
+                "NAME");
+    opts.optopt("",
+                "for",
+                "with unblock, block again after this many minutes (or e.g. 2h)",
+                "MINUTES");
+    opts.optopt("",
+                "format",
+                "output format: plain, tsv or json for list and status, plain, hosts, \
+                 dnsmasq, unbound or adblock for export",
+                "FMT");
     let matches = match opts.parse(&args[1..]) {
         Ok(m) => {
             m
         }
         Err(f) => { panic!(f.to_string()) }
     };
+
+    let config = load_config(&matches);
+
+    // Reading doesn't need root, don't make status bars and prompts run
+    // hostblock with sudo just to write a backup.
+    let read_only = match matches.free.first().map(|c| c.as_str()) {
+        Some("list") | Some("status") | Some("export") | Some("groups") | Some("journal") => true,
+        // A restore backs up the current file itself, once it knows which
+        // backup mustn't be pruned.
+        Some("backups") => true,
+        _ => matches.opt_present("h"),
+    };
+    if !read_only {
+        if let Err(e) = backup_hosts(&config, None) {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't back up {}: {}.  Try running with sudo.",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            exit(1);
+        }
+    }
+
+    // Backups are handled before the hosts file is parsed so a restore still
+    // works when the current one is a mess.
+    if matches.free.first().map(|c| c.as_str()) == Some("backups") &&
+       !matches.opt_present("h") {
+        exit(backups_command(&config, &matches.free[1..], matches.opt_present("y")));
+    }
+
+    let hosts = parse_hosts(&read_hosts(&config.hosts_path));
+    for warning in &hosts.warnings {
+        writeln!(&mut std::io::stderr(), "warning: {}", warning).unwrap();
+    }
+
+    let mut state = state_from_hosts(&config, hosts);
+
+    // Every run re-blocks what was only unblocked for a while, `expire` does
+    // the same but complains when it can't.
+    let command = matches.free.first().map(|c| c.as_str());
+    if !matches.opt_present("h") && command != Some("expire") {
+        let (expired, expired_state) = expire_unblocks(&state, now());
+        if !expired.is_empty() {
+            match save_hosts(&config, &expired_state) {
+                Ok(_) => state = expired_state,
+                Err(e) => {
+                    writeln!(&mut std::io::stderr(),
+                             "warning: couldn't block {} expired domains again: {}",
+                             expired.len(),
+                             e)
+                        .unwrap();
+                }
+            }
+        }
+    }
+
     if matches.opt_present("h") {
         print_usage(&program, opts);
-        return (false, state);
+        exit(0);
+    }
+    if !matches.free.is_empty() {
+        let code = match matches.free[0].as_str() {
+            "add" | "remove" | "block" | "unblock" => {
+                let until = match matches.opt_str("for") {
+                    Some(ref d) if matches.free[0] == "unblock" => {
+                        match parse_duration(d) {
+                            Some(secs) => Some(now().saturating_add(secs)),
+                            None => die(&format!("--for takes minutes like 10 or hours \
+                                                  like 2h, up to a week, got {}",
+                                                 d)),
+                        }
+                    }
+                    Some(_) => die("--for only works with unblock"),
+                    None => None,
+                };
+                domains_command(&config,
+                                state,
+                                &matches.free[0],
+                                &matches.free[1..],
+                                matches.opt_str("group").as_ref().map(|g| g.as_str()),
+                                until)
+            }
+            "group" if matches.free.len() > 2 => {
+                // "-" takes the domains out of whatever group they're in
+                let group = if matches.free[1] == "-" { "" } else { &matches.free[1] };
+                domains_command(&config, state, "group", &matches.free[2..], Some(group), None)
+            }
+            "groups" => groups_command(&state),
+            "journal" => journal_command(&config),
+            "schedule" => {
+                schedule_command(&config,
+                                 state,
+                                 &matches.free[1..],
+                                 matches.opt_str("group").as_ref().map(|g| g.as_str()))
+            }
+            "challenge" => {
+                challenge_command(&config,
+                                  state,
+                                  &matches.free[1..],
+                                  matches.opt_str("group").as_ref().map(|g| g.as_str()))
+            }
+            "apply" => apply_command(&config, &state),
+            "expire" => expire_command(&config, &state),
+            "daemon" => daemon_command(&config),
+            "serve" => serve_command(&config),
+            "import" => {
+                match matches.free.get(1) {
+                    Some(path) => import_command(&config, state, path),
+                    None => {
+                        writeln!(&mut std::io::stderr(), "Usage: import <file>|-").unwrap();
+                        2
+                    }
+                }
+            }
+            "export" => {
+                match ExportFormat::parse(matches.opt_str("format")
+                                                 .as_ref()
+                                                 .map_or("plain", |f| f)) {
+                    Some(format) => export_command(&state, format),
+                    None => {
+                        writeln!(&mut std::io::stderr(),
+                                 "--format must be one of plain, hosts, dnsmasq, unbound or \
+                                  adblock")
+                            .unwrap();
+                        2
+                    }
+                }
+            }
+            cmd @ "list" | cmd @ "status" => {
+                match Format::parse(matches.opt_str("format").as_ref().map_or("plain", |f| f)) {
+                    Some(format) if cmd == "list" => list_command(&state, format),
+                    Some(format) => status_command(&state, format),
+                    None => {
+                        writeln!(&mut std::io::stderr(),
+                                 "--format must be one of plain, tsv or json")
+                            .unwrap();
+                        2
+                    }
+                }
+            }
+            other => {
+                writeln!(&mut std::io::stderr(), "Unknown command: {}", other).unwrap();
+                print_usage(&program, opts);
+                2
+            }
+        };
+        exit(code);
     }
     if matches.opt_present("b") {
         print!("hosts blocked");
-        return (false, block_all(state));
+        return (false, config, block_all(state, None));
     }
     if matches.opt_present("u"){
         // doing it like this doesn't change the runtime behavior to much
         if cfg!(feature = "commandline_unblock"){
             // fall into the menu to allow the passphrase
-            return (true, unblock_all(state));
+            return (true, config, unblock_all(state, None));
         }
         print!("unblock via commandline disabled in this build");
-        return (false,state);
+        return (false, config, state);
+    }
+
+    return (true, config, state);
+}
+
+fn state_from_hosts(config: &Config, hosts: HostsFile) -> State {
+    let friction = friction_for(&load_unblocks(config), now());
+    // The addresses as they are in the file, save_hosts fills in the sinks.
+    let domains = hosts.domains;
+    State {
+        selected: 0,
+        loaded: domains.clone(),
+        domains: domains,
+        loaded_groups: hosts.groups.clone(),
+        group_settings: hosts.groups,
+        adding: String::from(""),
+        pass_input: String::from(""),
+        // Made for real by gate().
+        challenge: Puzzle {
+            title: String::new(),
+            prompt: String::new(),
+            answer: String::new(),
+        },
+        pass_words: 2,
+        challenges: config.challenges.clone(),
+        status: Status::Clean,
+        mode: Mode::Normal,
+        default_sink: config.sink.clone(),
+        group_selected: 0,
+        friction: friction,
+        gate: Mode::Password,
+        gate_opened: 0,
+        reason: String::new(),
+        reasons_left: 0,
+        passphrases: 0,
+        cooldown: config.cooldown,
+        cooldown_until: 0,
+        unlock_for: 0,
+        paste_check: config.paste_check,
+        last_key_ms: 0,
+        fast_keys: 0,
+        pasted: false,
+        journal: Vec::new(),
+        undo: Vec::new(),
+        redo: Vec::new(),
+        filter: String::new(),
+        // Set once the interface is up.
+        height: 0,
+        scroll: 0,
+        marked: Vec::new(),
+        anchor: 0,
+    }
+}
+
+// For the long running modes, which shouldn't die with the hosts file
+// briefly gone.
+fn load_state(config: &Config) -> Result<State, io::Error> {
+    let bytes = fs::read(&config.hosts_path)?;
+    Ok(state_from_hosts(config, parse_hosts(&String::from_utf8_lossy(&bytes))))
+}
+
+fn load_config(matches: &getopts::Matches) -> Config {
+    let config_path = matches.opt_str("config").or_else(|| env::var(CONFIG_PATH_VAR).ok());
+    let file = match read_config_file(Path::new(config_path.as_ref()
+                                                           .map_or(DEFAULT_CONFIG_PATH,
+                                                                   |p| p.as_str()))) {
+        Ok(file) => file,
+        // Only complain about a missing config file if it was asked for.
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound && config_path.is_none() => {
+            HashMap::new()
+        }
+        Err(e) => die(&format!("Couldn't read config file: {}", e)),
+    };
+    let setting = |opt: &str, var: &str| setting(matches, &file, opt, var);
+
+    let hosts_path = PathBuf::from(setting("hosts-file", HOSTS_PATH_VAR)
+                                       .unwrap_or_else(|| String::from(DEFAULT_HOSTS_PATH)));
+    let backup_dir = match setting("backup-dir", BACKUP_DIR_VAR) {
+        Some(dir) => PathBuf::from(dir),
+        None => {
+            match hosts_path.parent() {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+                _ => PathBuf::from("."),
+            }
+        }
+    };
+    let keep_backups = match setting("keep-backups", KEEP_BACKUPS_VAR) {
+        Some(n) => {
+            match n.parse::<usize>() {
+                Ok(n) if n > 0 => n,
+                _ => die(&format!("keep-backups must be a positive number, got {}", n)),
+            }
+        }
+        None => DEFAULT_KEEP_BACKUPS,
+    };
+    let sink = match setting("sink", SINK_VAR) {
+        Some(addr) => {
+            if addr.parse::<Ipv4Addr>().is_err() {
+                die(&format!("sink must be an IPv4 address, got {}", addr));
+            }
+            addr
+        }
+        None => String::from(DEFAULT_SINK),
+    };
+    let sink6 = match setting("sink6", SINK6_VAR) {
+        Some(ref addr) if addr == "none" => None,
+        Some(addr) => {
+            if addr.parse::<Ipv6Addr>().is_err() {
+                die(&format!("sink6 must be an IPv6 address, got {}", addr));
+            }
+            Some(addr)
+        }
+        None => Some(String::from(DEFAULT_SINK6)),
+    };
+
+    let state_dir = PathBuf::from(setting("state-dir", STATE_DIR_VAR)
+                                      .unwrap_or_else(|| String::from(DEFAULT_STATE_DIR)));
+
+    let cooldown = match setting("cooldown", COOLDOWN_VAR) {
+        Some(secs) => {
+            match secs.parse::<u64>() {
+                Ok(secs) => secs,
+                Err(_) => die(&format!("cooldown must be a number of seconds, got {}", secs)),
+            }
+        }
+        None => DEFAULT_COOLDOWN,
+    };
+    let challenge = setting("challenge", CHALLENGE_VAR).unwrap_or_else(|| String::from("words"));
+    if !CHALLENGE_KINDS.contains(&challenge.as_str()) {
+        die(&format!("challenge must be one of {}, got {}", CHALLENGE_KINDS.join(", "), challenge));
+    }
+    let wordlist = setting("wordlist", WORDLIST_VAR).map(PathBuf::from);
+    if let Some(ref path) = wordlist {
+        if let Err(e) = read_wordlist(path) {
+            die(&format!("can't use wordlist {}: {}", path.display(), e));
+        }
+    }
+    let paste_check = match setting("paste-check", PASTE_CHECK_VAR).as_ref().map(|s| s.as_str()) {
+        Some("on") | None => true,
+        Some("off") => false,
+        Some(other) => die(&format!("paste-check must be on or off, got {}", other)),
+    };
+    let socket_dir = PathBuf::from(setting("socket-dir", SOCKET_DIR_VAR)
+                                       .unwrap_or_else(|| String::from(DEFAULT_SOCKET_DIR)));
+
+    Config {
+        hosts_path: hosts_path,
+        backup_dir: backup_dir,
+        keep_backups: keep_backups,
+        sink: sink,
+        sink6: sink6,
+        state_dir: state_dir,
+        socket_dir: socket_dir,
+        unblock_group: setting("unblock-group", UNBLOCK_GROUP_VAR),
+        cooldown: cooldown,
+        challenges: ChallengeConfig {
+            default: challenge,
+            wordlist: wordlist,
+        },
+        paste_check: paste_check,
+    }
+}
+
+// One "key = value" per line, keys are the long option names with
+// underscores, e.g. "keep_backups = 20".  '#' starts a comment.
+fn read_config_file(path: &Path) -> Result<HashMap<String, String>, io::Error> {
+    let mut text = String::new();
+    File::open(path)?.read_to_string(&mut text)?;
+
+    let mut settings = HashMap::new();
+    for (i, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or("").trim();
+        if line.is_empty() {
+            continue;
+        }
+        match line.find('=') {
+            Some(eq) => {
+                settings.insert(String::from(line[..eq].trim()),
+                                String::from(line[eq + 1..].trim()));
+            }
+            None => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                          format!("{} line {}: expected \"key = value\"",
+                                                  path.display(),
+                                                  i + 1)))
+            }
+        }
     }
+    Ok(settings)
+}
+
+// Command line options win over the environment, which wins over the config
+// file.
+fn setting(matches: &getopts::Matches,
+           file: &HashMap<String, String>,
+           opt: &str,
+           var: &str)
+           -> Option<String> {
+    matches.opt_str(opt)
+           .or_else(|| env::var(var).ok())
+           .or_else(|| file.get(&opt.replace("-", "_")).cloned())
+}
 
-    return (true, state);
+fn die(msg: &str) -> ! {
+    writeln!(&mut std::io::stderr(), "{}", msg).unwrap();
+    exit(1);
 }
 
 fn handle_key(key: rustbox::Key, state: &State) -> (bool, State) {
-    match state.mode {
+    let (quit, new_state) = match state.mode {
         Mode::Normal => handle_normal_input(key, state),
         Mode::Insert => handle_insert_input(key, state),
         Mode::Password => handle_password_input(key, state),
         Mode::Help => handle_help_input(key, state),
+        Mode::Sink => handle_sink_input(key, state),
+        Mode::Groups => handle_groups_input(key, state),
+        Mode::Group => handle_group_input(key, state),
+        Mode::Unlock => handle_unlock_input(key, state),
+        Mode::Duration => handle_duration_input(key, state),
+        Mode::Reason => handle_reason_input(key, state),
+        Mode::Cooldown => handle_cooldown_input(key, state),
+        Mode::Search => handle_search_input(key, state),
+        Mode::Visual => handle_visual_input(key, state),
+    };
+    // Undo and redo move through the history themselves.
+    let new_state = scroll_to_selection(select_visible(new_state));
+    if new_state.undo.len() != state.undo.len() {
+        return (quit, new_state);
+    }
+    (quit, remember(state, new_state))
+}
+
+// Time passing without a key press.
+fn tick(state: &State) -> (bool, State) {
+    match state.mode {
+        // Counted here, calling it off during the countdown isn't an unblock.
+        Mode::Cooldown if now() >= state.cooldown_until => {
+            let mut state = state.clone();
+            state.passphrases += 1;
+            match state.gate {
+                Mode::Unlock => {
+                    let mut new_state = unblock_until(&state, now().saturating_add(state.unlock_for));
+                    // Undoing past it would block the domain again, and redoing
+                    // would unblock it without the passphrase.
+                    new_state.undo.clear();
+                    new_state.redo.clear();
+                    new_state.journal.push(JournalEntry {
+                        at: now(),
+                        domain: state.domains[state.selected].url.clone(),
+                        minutes: Some((state.unlock_for + 59) / 60),
+                        reason: state.reason.clone(),
+                    });
+                    (false, normal_mode(&new_state))
+                }
+                _ => (true, state),
+            }
+        }
+        _ => (false, state.clone()),
     }
 }
 
+fn handle_cooldown_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Esc | Key::Char('q') => normal_mode(state),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
 fn handle_normal_input(key: Key, state: &State) -> (bool, State) {
     let mut should_quit = false;
 
@@ -198,320 +1056,4084 @@ fn handle_normal_input(key: Key, state: &State) -> (bool, State) {
             should_quit = quit;
             new_state
         }
+        Key::Esc if !state.marked.is_empty() => clear_marks(state),
+        Key::Esc if !state.filter.is_empty() => clear_filter(state),
         Key::Esc => {
             let (quit, new_state) = attempt_quit(state);
             should_quit = quit;
             new_state
         }
+        Key::Char('/') => search_mode(state),
+        Key::Char('n') => move_sel(state, Movement::Down),
+        Key::Char('N') => move_sel(state, Movement::Up),
         Key::Char('i') => insert_mode(state),
         Key::Char('h') => help_mode(state),
         Key::Char('j') | Key::Down => move_sel(state, Movement::Down),
         Key::Char('k') | Key::Up => move_sel(state, Movement::Up),
         Key::Char('J') => move_sel(state, Movement::Bottom),
         Key::Char('K') => move_sel(state, Movement::Top),
+        Key::PageDown => move_sel(state, Movement::PageDown),
+        Key::PageUp => move_sel(state, Movement::PageUp),
+        Key::Ctrl('d') => move_sel(state, Movement::HalfPageDown),
+        Key::Ctrl('u') => move_sel(state, Movement::HalfPageUp),
+        Key::Char('g') => groups_mode(state),
+        Key::Char('u') => undo(state),
+        Key::Ctrl('r') => redo(state),
+        // d, space and a work on the marked domains when there are any.
+        Key::Char('d') if !state.marked.is_empty() => clear_marks(&delete_selected(state)),
+        Key::Char(' ') if !state.marked.is_empty() => clear_marks(&toggle_block(state)),
+        Key::Char('a') if !state.marked.is_empty() => group_mode(state),
+        // Nothing below works on a domain the filter hides.
+        _ if !has_selection(state) => state.clone(),
+        Key::Char('v') => visual_mode(state),
+        Key::Char('m') => toggle_mark(state),
         Key::Char('d') => delete_selected(state),
         Key::Char(' ') => toggle_block(state),
+        Key::Char('s') if !state.domains.is_empty() => sink_mode(state),
+        Key::Char('a') if !state.domains.is_empty() => group_mode(state),
+        Key::Char('t') if state.domains.get(state.selected).map_or(false, |d| {
+            d.status == DomainStatus::Blocked
+        }) => unlock_mode(state),
         _ => state.clone(),
     };
 
     (should_quit, new_state)
 }
 
-fn attempt_quit(state: &State) -> (bool, State) {
-    let mut should_quit = false;
+////////////
+// Search //
+////////////
+// `/` filters the list as you type: a pattern with * or ? is a glob over the
+// whole domain, anything else a substring, both ignoring case.  The filter
+// stays on after enter, n and N go through what it lets through and esc
+// clears it.
 
-    let new_state = match state.status {
-        Status::Dirty => password_mode(&state),
-        Status::Clean => {
-            should_quit = true;
-            state.clone()
+fn handle_search_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Enter => normal_mode(state),
+        Key::Esc => normal_mode(&clear_filter(state)),
+        Key::Backspace => {
+            let mut new_state = state.clone();
+            new_state.filter.pop();
+            select_visible(new_state)
+        }
+        Key::Char(c) => {
+            let mut new_state = state.clone();
+            new_state.filter.push(c);
+            select_visible(new_state)
         }
+        _ => state.clone(),
     };
 
-    (should_quit, new_state)
+    (false, new_state)
 }
 
-fn handle_help_input(key: Key, state: &State) -> (bool, State) {
-    let new_state = match key {
-        Key::Esc | Key::Char('q') => normal_mode(state),
-        Key::Char('i') => insert_mode(state),
-        Key::Char('h') => help_mode(state),
-        Key::Char(' ') => toggle_block(state),
-        _ => state.clone(),
-    };
+fn clear_filter(state: &State) -> State {
+    let mut new_state = state.clone();
+    new_state.filter = String::new();
+    new_state
+}
 
-    (false, new_state)
+// Keeps the selection on a shown domain, the first one if it was filtered
+// out.
+fn select_visible(state: State) -> State {
+    if has_selection(&state) {
+        return state;
+    }
+    let mut new_state = state;
+    new_state.selected = visible(&new_state).first().cloned().unwrap_or(new_state.selected);
+    new_state
 }
 
-fn handle_insert_input(key: Key, state: &State) -> (bool, State) {
-    let new_state = match key {
-        Key::Enter => {
-            let s = if state.adding.is_empty() {
-                state.clone()
-            } else {
-                add_url(&state, &state.adding)
-            };
-            normal_mode(&s)
-        }
-        Key::Esc => normal_mode(state),
-        Key::Backspace => backspace(state),
-        Key::Char(c) => add_char(state, c),
-        _ => state.clone(),
-    };
+fn has_selection(state: &State) -> bool {
+    state.selected < state.domains.len() && matches(&state.domains[state.selected], &state.filter)
+}
 
-    (false, new_state)
+// Indices of the domains the filter lets through.
+fn visible(state: &State) -> Vec<usize> {
+    (0..state.domains.len()).filter(|&i| matches(&state.domains[i], &state.filter)).collect()
 }
 
-fn handle_password_input(key: Key, state: &State) -> (bool, State) {
-    let mut should_quit = false;
+fn matches(domain: &Domain, filter: &str) -> bool {
+    let url = domain.url.to_lowercase();
+    let filter = filter.to_lowercase();
+    if filter.contains('*') || filter.contains('?') {
+        let (pattern, text): (Vec<char>, Vec<char>) = (filter.chars().collect(), url.chars().collect());
+        glob_match(&pattern, &text)
+    } else {
+        url.contains(&filter)
+    }
+}
 
-    let new_state = match key {
-        Key::Enter => {
-            if state.pass_input == state.correct_pass {
-                should_quit = true;
-                state.clone()
-            } else {
-                let mut new_state = state.clone();
-                new_state.pass_input = String::from("");
-                new_state
+// Runs on every domain for every key typed, so no backtracking beyond the
+// last '*': on a mismatch that star takes one more character and matching
+// carries on from there.
+fn glob_match(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // The last '*' seen, and where in the text it stopped matching.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some(&'*') => {
+                star = Some((p, t));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => {
+                match star {
+                    Some((star_p, star_t)) => {
+                        star = Some((star_p, star_t + 1));
+                        p = star_p + 1;
+                        t = star_t + 1;
+                    }
+                    None => return false,
+                }
             }
         }
-        Key::Esc => normal_mode(state),
-        Key::Backspace => password_backspace(state),
-        Key::Char(c) => add_password_char(state, c),
-        _ => state.clone(),
-    };
-
-    (should_quit, new_state)
+    }
+    pattern[p..].iter().all(|&c| c == '*')
 }
 
-////////////////////////////
-//  State manipulation   ///
-////////////////////////////
-fn move_sel(state: &State, movement: Movement) -> State {
-    let mut new_state = state.clone();
+///////////////
+// Selection //
+///////////////
+// `m` marks domains one at a time, `v` picks the range between where it was
+// pressed and the cursor.  Deleting, toggling and grouping then work on all
+// of them at once, and unblocking a batch still takes a single passphrase
+// when saving.
 
-    new_state.selected = match movement {
-        Movement::Top => 0,
-        Movement::Bottom => state.domains.len() - 1,
-        Movement::Up => {
-            if state.selected == 0 {
-                state.domains.len() - 1
-            } else {
-                state.selected - 1
-            }
-        }
-        Movement::Down => {
-            if state.selected == state.domains.len() - 1 {
-                0
-            } else {
-                state.selected + 1
-            }
-        }
+fn handle_visual_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Esc | Key::Char('v') | Key::Char('q') => normal_mode(state),
+        Key::Char('j') | Key::Down => move_sel(state, Movement::Down),
+        Key::Char('k') | Key::Up => move_sel(state, Movement::Up),
+        Key::Char('J') => move_sel(state, Movement::Bottom),
+        Key::Char('K') => move_sel(state, Movement::Top),
+        Key::PageDown => move_sel(state, Movement::PageDown),
+        Key::PageUp => move_sel(state, Movement::PageUp),
+        Key::Ctrl('d') => move_sel(state, Movement::HalfPageDown),
+        Key::Ctrl('u') => move_sel(state, Movement::HalfPageUp),
+        Key::Char('d') => clear_marks(&normal_mode(&delete_selected(state))),
+        Key::Char(' ') => clear_marks(&normal_mode(&toggle_block(state))),
+        // The range becomes marks so it outlives visual mode.
+        Key::Char('a') => group_mode(&mark_range(state)),
+        Key::Char('m') => normal_mode(&mark_range(state)),
+        _ => state.clone(),
     };
 
+    (false, new_state)
+}
+
+fn visual_mode(state: &State) -> State {
+    let mut new_state = switch_mode(state, Mode::Visual);
+    new_state.anchor = state.selected;
     new_state
 }
 
-fn switch_mode(state: &State, mode: Mode) -> State {
+// The domains an edit applies to: visual mode's range, the marked ones, or
+// just the selected one.
+fn targets(state: &State) -> Vec<usize> {
+    if let Mode::Visual = state.mode {
+        let rows = visible(state);
+        let row = |i| rows.iter().position(|&r| r == i);
+        return match (row(state.anchor), row(state.selected)) {
+            (Some(a), Some(b)) => rows[min(a, b)..max(a, b) + 1].to_vec(),
+            _ => vec![state.selected],
+        };
+    }
+    if !state.marked.is_empty() {
+        return (0..state.domains.len())
+                   .filter(|&i| state.marked.contains(&state.domains[i].url))
+                   .collect();
+    }
+    if state.selected < state.domains.len() {
+        vec![state.selected]
+    } else {
+        Vec::new()
+    }
+}
+
+fn toggle_mark(state: &State) -> State {
     let mut new_state = state.clone();
-    new_state.mode = mode;
+    let url = &state.domains[state.selected].url;
+    match state.marked.iter().position(|m| m == url) {
+        Some(i) => {
+            new_state.marked.remove(i);
+        }
+        None => new_state.marked.push(url.clone()),
+    }
     new_state
 }
-// TODO(cgag): just clone the state and mutate the individual field.
-fn normal_mode(state: &State) -> State {
-    switch_mode(state, Mode::Normal)
-}
 
-fn password_mode(state: &State) -> State {
-    switch_mode(state, Mode::Password)
+fn mark_range(state: &State) -> State {
+    let mut new_state = state.clone();
+    for i in targets(state) {
+        let url = &state.domains[i].url;
+        if !new_state.marked.contains(url) {
+            new_state.marked.push(url.clone());
+        }
+    }
+    new_state.mode = Mode::Normal;
+    new_state
 }
 
-fn insert_mode(state: &State) -> State {
-    switch_mode(state, Mode::Insert)
+fn clear_marks(state: &State) -> State {
+    let mut new_state = state.clone();
+    new_state.marked = Vec::new();
+    new_state
 }
 
-fn help_mode(state: &State) -> State {
-    switch_mode(state, Mode::Help)
+//////////////
+// Viewport //
+//////////////
+// The list shows what fits between its label and the lines under it, and
+// scrolls to keep the selected domain in view.
+
+// Rows of the list that fit on the screen: the label, the bottom border, the
+// search line and the history line take one each.
+fn list_rows(state: &State) -> usize {
+    max(state.height.saturating_sub(4), 1)
 }
 
-fn add_url(state: &State, url: &str) -> State {
+fn resize(state: &State, height: usize) -> State {
     let mut new_state = state.clone();
+    new_state.height = height;
+    scroll_to_selection(new_state)
+}
 
-    new_state.domains.push(Domain {
-        url: String::from(url),
-        status: DomainStatus::Blocked,
-    });
-    new_state.adding = "".to_owned();
+fn scroll_to_selection(state: State) -> State {
+    let rows = visible(&state);
+    let page = list_rows(&state);
+    let mut new_state = state;
+    if let Some(row) = rows.iter().position(|&i| i == new_state.selected) {
+        if row < new_state.scroll {
+            new_state.scroll = row;
+        } else if row >= new_state.scroll + page {
+            new_state.scroll = row + 1 - page;
+        }
+    }
+    // No empty rows at the bottom while there's more above.
+    new_state.scroll = min(new_state.scroll, rows.len().saturating_sub(page));
+    new_state
+}
 
+/////////////
+// History //
+/////////////
+// Every handler hands back a new state, so the history is just the old
+// lists.  A snapshot keeps the status too, so undoing back to the list as it
+// was loaded is clean again, and redoing an unblock needs the passphrase
+// again.
+
+// Adds `state` to the history of `new_state` if the key changed the list.
+fn remember(state: &State, new_state: State) -> State {
+    if new_state.domains == state.domains && new_state.group_settings == state.group_settings {
+        return new_state;
+    }
+    let mut new_state = new_state;
+    new_state.undo.push(snapshot(state, describe_edit(state, &new_state)));
+    if new_state.undo.len() > HISTORY {
+        new_state.undo.remove(0);
+    }
+    new_state.redo.clear();
     new_state
 }
 
-fn delete_selected(state: &State) -> State {
+fn snapshot(state: &State, what: String) -> Snapshot {
+    Snapshot {
+        what: what,
+        domains: state.domains.clone(),
+        group_settings: state.group_settings.clone(),
+        selected: state.selected,
+        status: state.status.clone(),
+    }
+}
+
+fn restore(state: &State, snapshot: &Snapshot) -> State {
     let mut new_state = state.clone();
-    new_state.domains.remove(state.selected);
-    new_state.selected = if state.selected > 0 {
-        state.selected - 1
-    } else {
-        0
-    };
-    new_state.status = Status::Dirty;
+    new_state.domains = snapshot.domains.clone();
+    new_state.group_settings = snapshot.group_settings.clone();
+    new_state.selected = min(snapshot.selected, snapshot.domains.len().saturating_sub(1));
+    new_state.status = snapshot.status.clone();
     new_state
 }
 
-fn add_char(state: &State, c: char) -> State {
+fn undo(state: &State) -> State {
     let mut new_state = state.clone();
-    new_state.adding.push(c);
-    new_state
+    match new_state.undo.pop() {
+        Some(before) => {
+            new_state.redo.push(snapshot(state, before.what.clone()));
+            restore(&new_state, &before)
+        }
+        None => new_state,
+    }
 }
 
-// TODO(cgag): these redundant fns (this and backspace) are smelly
-fn add_password_char(state: &State, c: char) -> State {
+fn redo(state: &State) -> State {
     let mut new_state = state.clone();
-    new_state.pass_input.push(c);
-    new_state
+    match new_state.redo.pop() {
+        Some(after) => {
+            new_state.undo.push(snapshot(state, after.what.clone()));
+            restore(&new_state, &after)
+        }
+        None => new_state,
+    }
+}
+
+// A few words on what changed between two lists, for the status line.
+fn describe_edit(state: &State, new_state: &State) -> String {
+    let (old, new) = (&state.domains, &new_state.domains);
+    if let Some(d) = new.iter().find(|d| !old.iter().any(|o| o.url == d.url)) {
+        return format!("add {}", d.url);
+    }
+    let deleted: Vec<&Domain> = old.iter().filter(|d| !new.iter().any(|n| n.url == d.url)).collect();
+    match deleted.len() {
+        0 => {}
+        1 => return format!("delete {}", deleted[0].url),
+        n => return format!("delete {} domains", n),
+    }
+    let changed: Vec<(&Domain, &Domain)> = old.iter()
+                                             .filter_map(|o| {
+                                                 new.iter()
+                                                    .find(|n| n.url == o.url && *n != o)
+                                                    .map(|n| (o, n))
+                                             })
+                                             .collect();
+    match changed.len() {
+        0 => String::from("group settings"),
+        1 => {
+            let (o, n) = changed[0];
+            if o.status != n.status {
+                match n.status {
+                    DomainStatus::Blocked => format!("block {}", n.url),
+                    DomainStatus::Unblocked => format!("unblock {}", n.url),
+                }
+            } else if o.group != n.group {
+                format!("regroup {}", n.url)
+            } else if o.sink != n.sink {
+                format!("sink of {}", n.url)
+            } else {
+                format!("edit {}", n.url)
+            }
+        }
+        n => format!("{} domains", n),
+    }
+}
+
+// The history under the list: what u and ctrl-r would do.
+fn history_line(state: &State) -> String {
+    let mut parts = Vec::new();
+    if let Some(last) = state.undo.last() {
+        parts.push(format!("u: undo {}", last.what));
+    }
+    if let Some(next) = state.redo.last() {
+        parts.push(format!("ctrl-r: redo {}", next.what));
+    }
+    if parts.is_empty() {
+        return String::new();
+    }
+    format!("{}  ({}/{})", parts.join("  "), state.undo.len(), HISTORY)
+}
+
+fn attempt_quit(state: &State) -> (bool, State) {
+    let mut should_quit = false;
+
+    let new_state = match state.status {
+        Status::Dirty => password_mode(&state),
+        Status::Clean => {
+            should_quit = true;
+            state.clone()
+        }
+    };
+
+    (should_quit, new_state)
+}
+
+fn handle_help_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Esc | Key::Char('q') => normal_mode(state),
+        Key::Char('i') => insert_mode(state),
+        Key::Char('h') => help_mode(state),
+        Key::Char(' ') => toggle_block(state),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_insert_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Enter => {
+            let s = if state.adding.is_empty() {
+                state.clone()
+            } else {
+                add_url(&state, &state.adding)
+            };
+            normal_mode(&s)
+        }
+        Key::Esc => normal_mode(state),
+        Key::Backspace => backspace(state),
+        Key::Char(c) => add_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_sink_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Enter => {
+            if state.adding.is_empty() || state.adding.parse::<IpAddr>().is_ok() {
+                normal_mode(&set_sink(state, &state.adding))
+            } else {
+                state.clone()
+            }
+        }
+        Key::Esc => {
+            let mut new_state = normal_mode(state);
+            new_state.adding = String::new();
+            new_state
+        }
+        Key::Backspace => backspace(state),
+        Key::Char(c) => add_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_groups_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Esc | Key::Char('q') | Key::Char('g') => normal_mode(state),
+        Key::Char('j') | Key::Down => move_group_sel(state, Movement::Down),
+        Key::Char('k') | Key::Up => move_group_sel(state, Movement::Up),
+        Key::Char('J') => move_group_sel(state, Movement::Bottom),
+        Key::Char('K') => move_group_sel(state, Movement::Top),
+        Key::Char(' ') => toggle_group(state),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_group_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Enter => {
+            if state.adding.is_empty() || valid_group(&state.adding) {
+                normal_mode(&clear_marks(&set_group(state, &state.adding)))
+            } else {
+                state.clone()
+            }
+        }
+        Key::Esc => {
+            let mut new_state = normal_mode(state);
+            new_state.adding = String::new();
+            new_state
+        }
+        Key::Backspace => backspace(state),
+        Key::Char(c) => add_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_password_input(key: Key, state: &State) -> (bool, State) {
+    let mut should_quit = false;
+    if waiting(state) > 0 && key != Key::Esc {
+        return (false, state.clone());
+    }
+
+    let new_state = match key {
+        Key::Enter => {
+            if state.pass_input == state.challenge.answer {
+                let (quit, new_state) = tick(&cooldown_mode(state));
+                should_quit = quit;
+                new_state
+            } else {
+                let mut new_state = state.clone();
+                new_state.pass_input = String::from("");
+                new_state
+            }
+        }
+        Key::Esc => normal_mode(state),
+        Key::Backspace => password_backspace(state),
+        Key::Char(c) => add_password_char(state, c),
+        _ => state.clone(),
+    };
+
+    (should_quit, new_state)
+}
+
+// The passphrase up front instead of on quit, so the timed unblock doesn't
+// leave the state dirty.
+fn handle_unlock_input(key: Key, state: &State) -> (bool, State) {
+    if waiting(state) > 0 && key != Key::Esc {
+        return (false, state.clone());
+    }
+
+    let new_state = match key {
+        Key::Enter => {
+            if state.pass_input == state.challenge.answer {
+                let mut new_state = switch_mode(state, Mode::Duration);
+                new_state.pass_input = String::new();
+                new_state.adding = String::new();
+                new_state
+            } else {
+                let mut new_state = state.clone();
+                new_state.pass_input = String::new();
+                new_state
+            }
+        }
+        Key::Esc => normal_mode(state),
+        Key::Backspace => password_backspace(state),
+        Key::Char(c) => add_password_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+// The first reason can be anything, every one after it has to match it.
+fn handle_reason_input(key: Key, state: &State) -> (bool, State) {
+    if waiting(state) > 0 && key != Key::Esc {
+        return (false, state.clone());
+    }
+
+    let new_state = match key {
+        Key::Enter if state.adding.trim().is_empty() => state.clone(),
+        Key::Enter if !state.reason.is_empty() && state.adding != state.reason => {
+            let mut new_state = state.clone();
+            new_state.adding = String::new();
+            new_state
+        }
+        Key::Enter => {
+            let mut new_state = state.clone();
+            new_state.reason = state.adding.clone();
+            new_state.adding = String::new();
+            new_state.reasons_left -= 1;
+            if new_state.reasons_left == 0 {
+                new_state.mode = state.gate.clone();
+            }
+            new_state
+        }
+        Key::Esc => {
+            let mut new_state = normal_mode(state);
+            new_state.adding = String::new();
+            new_state
+        }
+        Key::Backspace => backspace(state),
+        Key::Char(c) => add_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+fn handle_duration_input(key: Key, state: &State) -> (bool, State) {
+    let new_state = match key {
+        Key::Enter => {
+            match parse_duration(&state.adding) {
+                Some(secs) => {
+                    let mut new_state = cooldown_mode(state);
+                    new_state.unlock_for = secs;
+                    tick(&new_state).1
+                }
+                None => state.clone(),
+            }
+        }
+        Key::Esc => {
+            let mut new_state = normal_mode(state);
+            new_state.adding = String::new();
+            new_state
+        }
+        Key::Backspace => backspace(state),
+        Key::Char(c) => add_char(state, c),
+        _ => state.clone(),
+    };
+
+    (false, new_state)
+}
+
+////////////////////////////
+//  State manipulation   ///
+////////////////////////////
+// Moves through the domains the filter lets through.
+fn move_sel(state: &State, movement: Movement) -> State {
+    let mut new_state = state.clone();
+    let rows = visible(state);
+    if rows.is_empty() {
+        return new_state;
+    }
+    let row = rows.iter().position(|&i| i == state.selected);
+    let (page, last) = (list_rows(state), rows.len() - 1);
+    let half = max(page / 2, 1);
+
+    // Paging stops at the ends instead of wrapping around.
+    new_state.selected = rows[match (movement, row) {
+        (Movement::Top, _) => 0,
+        (Movement::Bottom, _) => last,
+        (Movement::Up, Some(0)) | (Movement::Up, None) => last,
+        (Movement::Up, Some(row)) => row - 1,
+        (Movement::Down, Some(row)) if row < last => row + 1,
+        (Movement::Down, _) => 0,
+        (Movement::PageUp, row) => row.unwrap_or(0).saturating_sub(page),
+        (Movement::PageDown, row) => min(row.unwrap_or(0) + page, last),
+        (Movement::HalfPageUp, row) => row.unwrap_or(0).saturating_sub(half),
+        (Movement::HalfPageDown, row) => min(row.unwrap_or(0) + half, last),
+    }];
+
+    new_state
+}
+
+fn switch_mode(state: &State, mode: Mode) -> State {
+    let mut new_state = state.clone();
+    new_state.mode = mode;
+    new_state
+}
+// TODO(cgag): just clone the state and mutate the individual field.
+fn normal_mode(state: &State) -> State {
+    switch_mode(state, Mode::Normal)
+}
+
+fn search_mode(state: &State) -> State {
+    switch_mode(state, Mode::Search)
+}
+
+fn password_mode(state: &State) -> State {
+    gate(state, Mode::Password)
+}
+
+// Everything that unblocks goes through here: first the wait, then the
+// reasons, and then `mode`, the passphrase screen.
+fn gate(state: &State, mode: Mode) -> State {
+    let mut new_state = state.clone();
+    new_state.challenge = new_challenge(state, &mode);
+    new_state.last_key_ms = 0;
+    new_state.fast_keys = 0;
+    new_state.pasted = false;
+    new_state.gate = mode.clone();
+    new_state.gate_opened = now();
+    new_state.pass_input = String::new();
+    new_state.adding = String::new();
+    new_state.reason = String::new();
+    new_state.reasons_left = state.friction.reasons;
+    new_state.mode = if state.friction.reasons > 0 { Mode::Reason } else { mode };
+    new_state
+}
+
+// Unblocking one domain takes its group's challenge, saving takes the
+// challenge of what this session unblocked.
+fn new_challenge(state: &State, mode: &Mode) -> Puzzle {
+    let group = match *mode {
+        Mode::Unlock => state.domains[state.selected].group.clone(),
+        _ => unblocked_group(state),
+    };
+    make_challenge(&state.challenges,
+                   &state.loaded_groups,
+                   group.as_ref().map(|g| g.as_str()),
+                   state.pass_words + state.friction.extra_words)
+}
+
+fn cooldown_mode(state: &State) -> State {
+    let mut new_state = switch_mode(state, Mode::Cooldown);
+    new_state.cooldown_until = now().saturating_add(state.cooldown);
+    new_state
+}
+
+// Seconds until the gate lets anything through.
+fn waiting(state: &State) -> u64 {
+    (state.gate_opened + state.friction.wait).saturating_sub(now())
+}
+
+fn unlock_mode(state: &State) -> State {
+    gate(state, Mode::Unlock)
+}
+
+fn insert_mode(state: &State) -> State {
+    switch_mode(state, Mode::Insert)
+}
+
+fn help_mode(state: &State) -> State {
+    switch_mode(state, Mode::Help)
+}
+
+fn groups_mode(state: &State) -> State {
+    let mut new_state = switch_mode(state, Mode::Groups);
+    new_state.group_selected = min(state.group_selected,
+                                   groups(&state.domains).len().saturating_sub(1));
+    new_state
+}
+
+// Starts out with the group of the first domain it's for.
+fn group_mode(state: &State) -> State {
+    let mut new_state = switch_mode(state, Mode::Group);
+    let first = targets(state).first().cloned().unwrap_or(state.selected);
+    new_state.adding = state.domains[first].group.clone().unwrap_or_default();
+    new_state
+}
+
+// Starts out with the selected domain's current sink so it can be edited.
+fn sink_mode(state: &State) -> State {
+    let mut new_state = switch_mode(state, Mode::Sink);
+    new_state.adding = state.domains[state.selected].sink.clone().unwrap_or_default();
+    new_state
+}
+
+fn add_url(state: &State, url: &str) -> State {
+    let mut new_state = state.clone();
+
+    new_state.domains.push(new_domain(url));
+    new_state.adding = "".to_owned();
+
+    new_state
+}
+
+fn new_domain(url: &str) -> Domain {
+    Domain {
+        url: String::from(url),
+        status: DomainStatus::Blocked,
+        ip: None,
+        ip6: None,
+        sink: None,
+        group: None,
+        schedule: None,
+        until: None,
+        aliases: Vec::new(),
+        comment: None,
+        leading: Vec::new(),
+        raw: None,
+    }
+}
+
+fn delete_selected(state: &State) -> State {
+    let mut new_state = state.clone();
+    let targets = targets(state);
+    let mut i = 0;
+    new_state.domains.retain(|_| {
+        i += 1;
+        !targets.contains(&(i - 1))
+    });
+    new_state.selected = targets.first().map_or(0, |&first| first.saturating_sub(1));
+    new_state.status = Status::Dirty;
+    new_state
+}
+
+// Pointing a domain somewhere else can just as well unblock it, so this needs
+// the passphrase like any other unblock.
+fn set_sink(state: &State, sink: &str) -> State {
+    let mut new_state = state.clone();
+    new_state.domains[state.selected].sink = if sink.is_empty() {
+        None
+    } else {
+        Some(String::from(sink))
+    };
+    new_state.adding = String::new();
+    new_state.status = Status::Dirty;
+    new_state
+}
+
+fn add_char(state: &State, c: char) -> State {
+    let mut new_state = state.clone();
+    new_state.adding.push(c);
+    new_state
+}
+
+// TODO(cgag): these redundant fns (this and backspace) are smelly
+fn add_password_char(state: &State, c: char) -> State {
+    let mut new_state = state.clone();
+    let at = now_millis();
+    if state.paste_check && at.saturating_sub(state.last_key_ms) < MIN_KEY_GAP_MS {
+        new_state.fast_keys += 1;
+        if new_state.fast_keys >= FAST_KEYS {
+            return reject_paste(state);
+        }
+    } else {
+        new_state.fast_keys = 0;
+    }
+    new_state.last_key_ms = at;
+    new_state.pasted = false;
+    new_state.pass_input.push(c);
+    new_state
+}
+
+fn backspace(state: &State) -> State {
+    let mut new_state = state.clone();
+    new_state.adding.pop();
+    new_state
+}
+
+fn password_backspace(state: &State) -> State {
+    let mut new_state = state.clone();
+    new_state.pass_input.pop();
+    new_state
+}
+
+// With a group only touches the domains in it.
+fn block_all(state:State, group: Option<&str>) -> State{
+    let mut new_state = state.clone();
+    new_state.domains = new_state.domains.into_iter().map(|domain| {
+        if in_group(&domain, group) {
+            Domain{ status:DomainStatus::Blocked, until: None, ..domain }
+        } else {
+            domain
+        }
+    }).collect();
+    new_state
+}
+
+fn unblock_all(state:State, group: Option<&str>) -> State{
+    let mut new_state = state.clone();
+    new_state.domains = new_state.domains.into_iter().map(|domain| {
+        if in_group(&domain, group) {
+            Domain{ status:DomainStatus::Unblocked, until: None, ..domain }
+        } else {
+            domain
+        }
+    }).collect();
+    new_state.status = Status::Dirty;
+    new_state.pass_words = 6;
+    gate(&new_state, Mode::Password)
+}
+
+fn in_group(domain: &Domain, group: Option<&str>) -> bool {
+    match group {
+        Some(group) => domain.group.as_ref().map_or(false, |g| g == group),
+        None => true,
+    }
+}
+
+// Group names with how many of their domains are blocked, sorted by name.
+fn groups(domains: &[Domain]) -> Vec<(String, usize, usize)> {
+    let mut groups: Vec<(String, usize, usize)> = Vec::new();
+    for domain in domains {
+        if let Some(ref name) = domain.group {
+            let blocked = if domain.status == DomainStatus::Blocked { 1 } else { 0 };
+            match groups.iter().position(|g| &g.0 == name) {
+                Some(i) => {
+                    groups[i].1 += blocked;
+                    groups[i].2 += 1;
+                }
+                None => groups.push((name.clone(), blocked, 1)),
+            }
+        }
+    }
+    groups.sort();
+    groups
+}
+
+// Another group can mean another schedule or an easier challenge for a
+// domain that was already in the file, so that needs the passphrase too.
+fn set_group(state: &State, group: &str) -> State {
+    let mut new_state = state.clone();
+    let targets = targets(state);
+    for &i in &targets {
+        new_state.domains[i].group = if group.is_empty() {
+            None
+        } else {
+            Some(String::from(group))
+        };
+    }
+    let guarded = |s: &State, i: usize| {
+        (schedule_for(&s.domains[i], &s.group_settings).map(String::from),
+         group_challenge(&s.domains[i], &s.group_settings).map(String::from))
+    };
+    if targets.iter().any(|&i| {
+        state.loaded.iter().any(|d| d.url == state.domains[i].url) &&
+        guarded(state, i) != guarded(&new_state, i)
+    }) {
+        new_state.status = Status::Dirty;
+    }
+    new_state.adding = String::new();
+    new_state
+}
+
+// Changing when a domain is blocked can unblock it at the next apply, so this
+// needs the passphrase like any other unblock.
+fn set_schedule(state: &State, schedule: Option<&str>) -> State {
+    let mut new_state = state.clone();
+    new_state.domains[state.selected].schedule = schedule.map(String::from);
+    new_state.status = Status::Dirty;
+    new_state
+}
+
+fn set_group_schedule(state: &State, group: &str, schedule: Option<&str>) -> State {
+    let mut new_state = state.clone();
+    group_settings(&mut new_state, group).schedule = schedule.map(String::from);
+    new_state.status = Status::Dirty;
+    new_state
+}
+
+// An easier challenge is as good as an unblock.
+fn set_group_challenge(state: &State, group: &str, challenge: Option<&str>) -> State {
+    let mut new_state = state.clone();
+    group_settings(&mut new_state, group).challenge = challenge.map(String::from);
+    new_state.status = Status::Dirty;
+    new_state
+}
+
+fn group_settings<'a>(state: &'a mut State, group: &str) -> &'a mut GroupSettings {
+    match state.group_settings.iter().position(|g| g.name == group) {
+        Some(i) => &mut state.group_settings[i],
+        None => {
+            state.group_settings.push(GroupSettings {
+                name: String::from(group),
+                schedule: None,
+                challenge: None,
+                raw: None,
+            });
+            state.group_settings.last_mut().unwrap()
+        }
+    }
+}
+
+// Blocks the highlighted group unless it's already fully blocked, in which
+// case the whole group is unblocked behind the long passphrase.
+fn toggle_group(state: &State) -> State {
+    let groups = groups(&state.domains);
+    match groups.get(state.group_selected) {
+        Some(&(ref name, blocked, total)) if blocked == total => {
+            unblock_all(state.clone(), Some(name))
+        }
+        Some(&(ref name, _, _)) => block_all(state.clone(), Some(name)),
+        None => state.clone(),
+    }
+}
+
+fn move_group_sel(state: &State, movement: Movement) -> State {
+    let count = groups(&state.domains).len();
+    let mut new_state = state.clone();
+    if count == 0 {
+        return new_state;
+    }
+
+    new_state.group_selected = match movement {
+        Movement::Top => 0,
+        Movement::Bottom => count - 1,
+        Movement::Up => (state.group_selected + count - 1) % count,
+        Movement::Down => (state.group_selected + 1) % count,
+        // There are never more groups than fit on a page.
+        Movement::PageUp | Movement::HalfPageUp => 0,
+        Movement::PageDown | Movement::HalfPageDown => count - 1,
+    };
+    new_state
+}
+
+// With several domains, blocks them all if any isn't, otherwise unblocks
+// them all.  Unblocking is saved behind one passphrase for the lot.
+fn toggle_block(state: &State) -> State {
+    let mut new_state = state.clone();
+    let targets = targets(state);
+    let unblock = targets.iter().all(|&i| state.domains[i].status == DomainStatus::Blocked);
+
+    let mut d = state.domains.clone();
+    for &i in &targets {
+        d[i].status = if unblock {
+            DomainStatus::Unblocked
+        } else {
+            DomainStatus::Blocked
+        };
+        // Either way it's no longer a temporary unblock.
+        d[i].until = None;
+    }
+    new_state.domains = d;
+    if unblock && !targets.is_empty() {
+        new_state.status = Status::Dirty;
+    }
+
+    new_state
+}
+
+// Only reached after the passphrase, so it doesn't dirty the state.
+fn unblock_until(state: &State, until: u64) -> State {
+    let mut new_state = state.clone();
+    new_state.domains[state.selected].status = DomainStatus::Unblocked;
+    new_state.domains[state.selected].until = Some(until);
+    new_state.adding = String::new();
+    new_state
+}
+
+// Blocks every temporary unblock that ran out by `secs`.
+fn expire_unblocks(state: &State, secs: u64) -> (Vec<String>, State) {
+    let mut new_state = state.clone();
+    let mut expired = Vec::new();
+    for domain in &mut new_state.domains {
+        if domain.until.map_or(false, |until| until <= secs) {
+            domain.status = DomainStatus::Blocked;
+            domain.until = None;
+            expired.push(format!("blocked {}", domain.url));
+        }
+    }
+    (expired, new_state)
+}
+
+// A temporary unblock longer than this might as well be a real one.
+static MAX_UNBLOCK: u64 = 7 * DAY;
+
+// "90", "90m" or "2h", in seconds, at most MAX_UNBLOCK.
+fn parse_duration(s: &str) -> Option<u64> {
+    let (n, unit) = if s.ends_with('h') {
+        (&s[..s.len() - 1], 3600)
+    } else if s.ends_with('m') {
+        (&s[..s.len() - 1], 60)
+    } else {
+        (s, 60)
+    };
+    match n.parse::<u64>() {
+        Ok(n) if n > 0 => n.checked_mul(unit).filter(|&secs| secs <= MAX_UNBLOCK),
+        _ => None,
+    }
+}
+
+/////////////////
+// Persistence //
+/////////////////
+fn read_hosts(path: &Path) -> String {
+    let mut hosts_file = match File::open(path) {
+        Ok(file) => file,
+        Err(_) => panic!("Couldn't access {}, try running with sudo.", path.display()),
+    };
+
+    // TODO(cgag): just return file handle so it's not all read into memory?
+    // We just iterate over the lines atm.
+    let mut s = String::new();
+    match hosts_file.read_to_string(&mut s) {
+        Ok(_) => {}
+        Err(e) => panic!("Couldn't read hosts file: {}", e),
+    }
+    s
+}
+
+fn parse_hosts(hosts_text: &str) -> HostsFile {
+    let mut lines: Vec<&str> = hosts_text.split('\n').collect();
+    // split leaves an empty last element when the text ends with a newline
+    let ends_with_newline = !hosts_text.is_empty() && lines.last() == Some(&"");
+    if lines.last() == Some(&"") {
+        lines.pop();
+    }
+
+    let mut hosts = HostsFile {
+        before: Vec::new(),
+        header: None,
+        domains: Vec::new(),
+        trailing: Vec::new(),
+        footer: None,
+        after: Vec::new(),
+        ends_with_newline: ends_with_newline,
+        warnings: Vec::new(),
+        groups: Vec::new(),
+    };
+
+    let start = match lines.iter().position(|l| l.starts_with(SECTION_START)) {
+        Some(start) => start,
+        None => {
+            hosts.before = lines.iter().map(|l| String::from(*l)).collect();
+            return hosts;
+        }
+    };
+    let end = match lines[start..].iter().position(|l| l.starts_with(SECTION_END)) {
+        Some(end) => start + end,
+        None => {
+            hosts.warnings.push(format!("no \"{}\" line, treating the rest of the file \
+                                         as managed",
+                                        SECTION_END));
+            lines.len()
+        }
+    };
+
+    hosts.before = lines[..start].iter().map(|l| String::from(*l)).collect();
+    hosts.header = Some(String::from(lines[start]));
+    if end < lines.len() {
+        hosts.footer = Some(String::from(lines[end]));
+        hosts.after = lines[end + 1..].iter().map(|l| String::from(*l)).collect();
+    }
+
+    let mut pending = Vec::new();
+    for (i, line) in lines[start + 1..end].iter().enumerate() {
+        if let Some(group) = parse_group_line(line) {
+            if let Some(ref schedule) = group.schedule {
+                if let Err(e) = parse_schedule(schedule) {
+                    hosts.warnings.push(format!("line {}: {}", start + i + 2, e));
+                }
+            }
+            if let Some(ref challenge) = group.challenge {
+                if !CHALLENGE_KINDS.contains(&challenge.as_str()) {
+                    hosts.warnings.push(format!("line {}: unknown challenge \"{}\"",
+                                                start + i + 2,
+                                                challenge));
+                }
+            }
+            hosts.groups.push(group);
+            continue;
+        }
+        match parse_entry(line) {
+            Some(mut domain) => {
+                // Only fold lines that sit right next to each other.
+                if pending.is_empty() {
+                    if let Some(prev) = hosts.domains.pop() {
+                        match merge_entries(prev, domain) {
+                            Ok(merged) => {
+                                hosts.domains.push(merged);
+                                continue;
+                            }
+                            Err((prev, next)) => {
+                                hosts.domains.push(prev);
+                                domain = next;
+                            }
+                        }
+                    }
+                }
+                if let Some(ref schedule) = domain.schedule {
+                    if let Err(e) = parse_schedule(schedule) {
+                        hosts.warnings.push(format!("line {}: {}", start + i + 2, e));
+                    }
+                }
+                domain.leading = pending;
+                pending = Vec::new();
+                hosts.domains.push(domain);
+            }
+            None => {
+                let trimmed = line.trim();
+                if !trimmed.is_empty() && !trimmed.starts_with('#') {
+                    hosts.warnings.push(format!("line {}: can't parse \"{}\", leaving it as is",
+                                                start + i + 2,
+                                                trimmed));
+                }
+                pending.push(String::from(*line));
+            }
+        }
+    }
+    hosts.trailing = pending;
+
+    hosts
+}
+
+// Parses "[#]<ip> <url> [aliases...] [# comment]", a leading '#' marks an
+// unblocked entry.  Anything else, including plain comments, is None.
+fn parse_entry(line: &str) -> Option<Domain> {
+    let (status, rest) = if line.starts_with('#') {
+        (DomainStatus::Unblocked, &line[1..])
+    } else {
+        (DomainStatus::Blocked, line)
+    };
+
+    let (body, meta, comment) = match rest.find('#') {
+        Some(i) => {
+            let (meta, comment) = split_meta(&rest[i + 1..]);
+            (&rest[..i], meta, comment)
+        }
+        None => (rest, HashMap::new(), None),
+    };
+
+    let mut fields = body.split_whitespace();
+    let (ip, ip6) = match fields.next().map(|ip| (ip, ip.parse::<IpAddr>())) {
+        Some((ip, Ok(IpAddr::V4(_)))) => (Some(String::from(ip)), None),
+        Some((ip, Ok(IpAddr::V6(_)))) => (None, Some(String::from(ip))),
+        _ => return None,
+    };
+    let url = match fields.next() {
+        Some(url) => String::from(url),
+        None => return None,
+    };
+
+    // An own sink is kept as `sink:<addr>`, whatever the address.  Untagged
+    // entries added by hand only keep theirs if it can't be the sink of the
+    // day, i.e. isn't a loopback or unspecified address.
+    let sink = meta.get("sink")
+                   .filter(|s| s.parse::<IpAddr>().is_ok())
+                   .cloned()
+                   .or_else(|| {
+                       ip.iter()
+                         .chain(ip6.iter())
+                         .find(|ip| {
+                             ip.parse::<IpAddr>()
+                               .map(|ip| !ip.is_loopback() && !ip.is_unspecified())
+                               .unwrap_or(false)
+                         })
+                         .cloned()
+                   });
+
+    Some(Domain {
+        url: url,
+        status: status,
+        ip: ip,
+        ip6: ip6,
+        sink: sink,
+        group: meta.get("group").cloned(),
+        schedule: meta.get("schedule").cloned(),
+        until: meta.get("until").and_then(|t| t.parse().ok()),
+        aliases: fields.map(String::from).collect(),
+        comment: comment,
+        leading: Vec::new(),
+        raw: Some(String::from(line)),
+    })
+}
+
+// Folds an IPv4 and an IPv6 line for the same name into one entry, handing
+// both back untouched if they don't belong together.
+fn merge_entries(prev: Domain, next: Domain) -> Result<Domain, (Domain, Domain)> {
+    let fits = prev.url == next.url && prev.status == next.status &&
+               prev.aliases == next.aliases &&
+               (render_comment(&next).is_none() ||
+                render_comment(&next) == render_comment(&prev)) &&
+               ((prev.ip.is_none() && next.ip6.is_none()) ||
+                (prev.ip6.is_none() && next.ip.is_none()));
+    if !fits {
+        return Err((prev, next));
+    }
+
+    let raw = match (prev.raw.as_ref(), next.raw.as_ref()) {
+        (Some(a), Some(b)) => Some(format!("{}\n{}", a, b)),
+        _ => None,
+    };
+    Ok(Domain {
+        ip: prev.ip.or(next.ip),
+        ip6: prev.ip6.or(next.ip6),
+        sink: prev.sink.or(next.sink),
+        raw: raw,
+        ..prev
+    })
+}
+
+fn same_entry(a: &Domain, b: &Domain) -> bool {
+    a.url == b.url && a.status == b.status && a.ip == b.ip && a.ip6 == b.ip6 &&
+    a.aliases == b.aliases && a.comment == b.comment && a.group == b.group &&
+    a.schedule == b.schedule && a.until == b.until && a.sink == b.sink
+}
+
+fn split_meta(comment: &str) -> (HashMap<String, String>, Option<String>) {
+    let mut meta = HashMap::new();
+    let mut note = Vec::new();
+    for word in comment.split_whitespace() {
+        match word.find(':') {
+            Some(i) if META_KEYS.contains(&&word[..i]) && i + 1 < word.len() => {
+                meta.insert(String::from(&word[..i]), String::from(&word[i + 1..]));
+            }
+            _ => note.push(word),
+        }
+    }
+    let note = if note.is_empty() {
+        None
+    } else {
+        Some(note.join(" "))
+    };
+    (meta, note)
+}
+
+fn render_comment(domain: &Domain) -> Option<String> {
+    let mut words = Vec::new();
+    if let Some(ref group) = domain.group {
+        words.push(format!("group:{}", group));
+    }
+    if let Some(ref schedule) = domain.schedule {
+        words.push(format!("schedule:{}", schedule));
+    }
+    if let Some(until) = domain.until {
+        words.push(format!("until:{}", until));
+    }
+    if let Some(ref sink) = domain.sink {
+        words.push(format!("sink:{}", sink));
+    }
+    if let Some(ref note) = domain.comment {
+        words.push(note.clone());
+    }
+    if words.is_empty() {
+        None
+    } else {
+        Some(format!("# {}", words.join(" ")))
+    }
+}
+
+// Works out the address each of an entry's lines should point at: its own
+// sink for that address family if it has one, the global sink otherwise.
+// Domains added in the TUI or written before IPv6 blocking existed get both
+// lines on the next save.
+fn fill_sinks(domain: &Domain, config: &Config) -> Domain {
+    let mut domain = domain.clone();
+    let own = domain.sink.as_ref().and_then(|s| s.parse::<IpAddr>().ok());
+
+    domain.ip = match own {
+        Some(IpAddr::V4(_)) => domain.sink.clone(),
+        _ => Some(config.sink.clone()),
+    };
+    domain.ip6 = match own {
+        Some(IpAddr::V6(_)) => domain.sink.clone(),
+        _ => config.sink6.clone(),
+    };
+    domain
+}
+
+fn render_entry(domain: &Domain) -> String {
+    if let Some(ref raw) = domain.raw {
+        let mut orig = raw.split('\n').map(parse_entry);
+        let unchanged = match orig.next() {
+            Some(Some(first)) => {
+                orig.fold(Ok(first), |acc, next| {
+                        match (acc, next) {
+                            (Ok(acc), Some(next)) => merge_entries(acc, next).map_err(|_| ()),
+                            _ => Err(()),
+                        }
+                    })
+                    .map(|orig| same_entry(&orig, domain))
+                    .unwrap_or(false)
+            }
+            _ => false,
+        };
+        if unchanged {
+            return raw.clone();
+        }
+    }
+
+    let block_marker = match domain.status {
+        DomainStatus::Blocked => "",
+        DomainStatus::Unblocked => "#",
+    };
+    let mut lines = Vec::new();
+    for ip in domain.ip.iter().chain(domain.ip6.iter()) {
+        let mut line = format!("{}{}\t{}", block_marker, ip, domain.url);
+        for alias in &domain.aliases {
+            line.push(' ');
+            line.push_str(alias);
+        }
+        // Once is enough, the paired line is folded back in on read.
+        if lines.is_empty() {
+            if let Some(comment) = render_comment(domain) {
+                line.push('\t');
+                line.push_str(&comment);
+            }
+        }
+        lines.push(line);
+    }
+    lines.join("\n")
+}
+
+fn parse_group_line(line: &str) -> Option<GroupSettings> {
+    if !line.starts_with(GROUP_LINE) {
+        return None;
+    }
+    let rest = &line[GROUP_LINE.len()..];
+    let mut words = rest.split_whitespace();
+    let name = match words.next() {
+        Some(name) if valid_group(name) => String::from(name),
+        _ => return None,
+    };
+    let (meta, _) = split_meta(&words.collect::<Vec<_>>().join(" "));
+
+    Some(GroupSettings {
+        name: name,
+        schedule: meta.get("schedule").cloned(),
+        challenge: meta.get("challenge").cloned(),
+        raw: Some(String::from(line)),
+    })
+}
+
+// None once there's nothing left to say about the group.
+fn render_group_line(group: &GroupSettings) -> Option<String> {
+    if let Some(ref raw) = group.raw {
+        if parse_group_line(raw).map_or(false, |orig| {
+            orig.schedule == group.schedule && orig.challenge == group.challenge
+        }) {
+            return Some(raw.clone());
+        }
+    }
+
+    let mut words = Vec::new();
+    if let Some(ref schedule) = group.schedule {
+        words.push(format!("schedule:{}", schedule));
+    }
+    if let Some(ref challenge) = group.challenge {
+        words.push(format!("challenge:{}", challenge));
+    }
+    if words.is_empty() {
+        None
+    } else {
+        Some(format!("{}{} {}", GROUP_LINE, group.name, words.join(" ")))
+    }
+}
+
+fn render_hosts(hosts: &HostsFile) -> String {
+    let mut lines: Vec<&str> = hosts.before.iter().map(|l| l.as_str()).collect();
+    let groups: Vec<String> = hosts.groups.iter().filter_map(render_group_line).collect();
+    let entries: Vec<String> = hosts.domains.iter().map(render_entry).collect();
+
+    // Don't grow an empty section in a file that never had one.
+    if hosts.header.is_some() || !hosts.domains.is_empty() || !groups.is_empty() {
+        lines.push(hosts.header.as_ref().map_or(SECTION_START, |h| h.as_str()));
+        lines.extend(groups.iter().map(|l| l.as_str()));
+        for (domain, entry) in hosts.domains.iter().zip(&entries) {
+            lines.extend(domain.leading.iter().map(|l| l.as_str()));
+            lines.push(entry);
+        }
+        lines.extend(hosts.trailing.iter().map(|l| l.as_str()));
+        // A section that ran to the end of the file keeps doing so.
+        if hosts.footer.is_some() || hosts.header.is_none() {
+            lines.push(hosts.footer.as_ref().map_or(SECTION_END, |f| f.as_str()));
+        }
+    }
+    lines.extend(hosts.after.iter().map(|l| l.as_str()));
+
+    let mut text = lines.join("\n");
+    if hosts.ends_with_newline || (hosts.header.is_none() && lines.len() > hosts.before.len()) {
+        text.push('\n');
+    }
+    text
+}
+
+fn save_hosts(config: &Config, state: &State) -> Result<(), io::Error> {
+    let path = &config.hosts_path;
+    let mut hosts_file = File::open(path)?;
+    let mut hosts_text = String::new();
+    hosts_file.read_to_string(&mut hosts_text)?;
+
+    // Re-read so edits made outside the section while we ran survive.
+    let mut hosts = parse_hosts(&hosts_text);
+    hosts.domains = state.domains.iter().map(|d| fill_sinks(d, config)).collect();
+    hosts.groups = state.group_settings.clone();
+
+    // Recorded first: if writing the hosts file fails after this the daemon
+    // only puts in what was asked for anyway.  Not being able to is only a
+    // warning, the state dir needn't be writable without a daemon running.
+    if let Err(e) = record_section(config, &render_section(&hosts)) {
+        writeln!(&mut std::io::stderr(), "warning: couldn't record the section: {}", e).unwrap();
+    }
+
+    let new_hosts = render_hosts(&hosts);
+    if new_hosts == hosts_text {
+        return Ok(());
+    }
+    write_atomically(path, new_hosts.as_bytes(), HOSTS_MODE)
+}
+
+// Just the managed section, markers included, or nothing without one.
+fn render_section(hosts: &HostsFile) -> String {
+    let section = render_hosts(&HostsFile {
+        before: Vec::new(),
+        after: Vec::new(),
+        ends_with_newline: true,
+        ..hosts.clone()
+    });
+    if section.trim().is_empty() { String::new() } else { section }
+}
+
+fn record_section(config: &Config, section: &str) -> Result<(), io::Error> {
+    let path = config.section_path();
+    if fs::read(&path).ok().map_or(false, |old| old == section.as_bytes()) {
+        return Ok(());
+    }
+    fs::create_dir_all(&config.state_dir)
+        .map_err(|e| context(e, &format!("couldn't create {}", config.state_dir.display())))?;
+    write_atomically(&path, section.as_bytes(), 0o600)
+}
+
+// Never truncate the real file in place: a crash halfway through would leave
+// the machine without a hosts file.  Instead write a temp file in the same
+// directory, fsync it, give it the original's mode, owner and xattrs, and
+// rename it over the target.  Without an original it gets `mode`.
+//
+// A symlinked hosts file is followed, so the link stays a link.  A bind
+// mounted one, as in containers, can't be renamed over; that falls back to
+// writing it in place.
+fn write_atomically(path: &Path, contents: &[u8], mode: u32) -> Result<(), io::Error> {
+    let resolved = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+    let path = resolved.as_path();
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let file_name = match path.file_name() {
+        Some(name) => name.to_string_lossy().into_owned(),
+        None => {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput,
+                                      format!("{} is not a file", path.display())))
+        }
+    };
+    let tmp_path = dir.join(format!(".{}.hb-tmp.{}", file_name, process::id()));
+    // Only a crashed run that had our pid can have left this behind.
+    let _ = fs::remove_file(&tmp_path);
+
+    let result = write_temp(path, &tmp_path, contents, mode)
+        .and_then(|_| {
+            match fs::rename(&tmp_path, path) {
+                Err(ref e) if e.raw_os_error() == Some(libc::EBUSY) ||
+                              e.raw_os_error() == Some(libc::EXDEV) => {
+                    let _ = fs::remove_file(&tmp_path);
+                    write_in_place(path, contents)
+                }
+                result => {
+                    result.map_err(|e| {
+                        context(e,
+                                &format!("couldn't move {} over {}",
+                                         tmp_path.display(),
+                                         path.display()))
+                    })
+                }
+            }
+        })
+        .and_then(|_| {
+            File::open(dir)
+                .and_then(|d| d.sync_all())
+                .map_err(|e| context(e, &format!("couldn't sync {}", dir.display())))
+        });
+
+    if result.is_err() {
+        let _ = fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+fn write_in_place(path: &Path, contents: &[u8]) -> Result<(), io::Error> {
+    fs::OpenOptions::new()
+        .write(true)
+        .truncate(true)
+        .open(path)
+        .and_then(|mut file| file.write_all(contents).and_then(|_| file.sync_all()))
+        .map_err(|e| context(e, &format!("couldn't write {}", path.display())))
+}
+
+fn write_temp(original: &Path,
+              tmp_path: &Path,
+              contents: &[u8],
+              mode: u32)
+              -> Result<(), io::Error> {
+    let in_tmp = |e: io::Error| context(e, &format!("couldn't write {}", tmp_path.display()));
+
+    let mut tmp = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(tmp_path)
+        .map_err(&in_tmp)?;
+
+    tmp.write_all(contents).map_err(&in_tmp)?;
+
+    // A missing original is fine, the new file just gets the given mode.
+    // Set explicitly, the umask doesn't get a say in whether everyone can
+    // read a recreated /etc/hosts.
+    match File::open(original) {
+        Ok(orig) => {
+            let meta = orig.metadata().map_err(|e| {
+                context(e, &format!("couldn't stat {}", original.display()))
+            })?;
+            copy_owner(&meta, &tmp).map_err(&in_tmp)?;
+            copy_xattrs(&orig, &tmp).map_err(&in_tmp)?;
+            // Set the mode last, chown clears setuid/setgid bits.
+            tmp.set_permissions(meta.permissions()).map_err(&in_tmp)?;
+        }
+        Err(_) => tmp.set_permissions(fs::Permissions::from_mode(mode)).map_err(&in_tmp)?,
+    }
+
+    tmp.sync_all().map_err(&in_tmp)
+}
+
+fn copy_owner(meta: &fs::Metadata, to: &File) -> Result<(), io::Error> {
+    use std::os::unix::fs::MetadataExt;
+
+    if unsafe { libc::fchown(to.as_raw_fd(), meta.uid(), meta.gid()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Carries over security labels (SELinux, SMACK, ...) and any other extended
+// attributes, filesystems without xattr support are silently skipped.
+#[cfg(target_os = "linux")]
+fn copy_xattrs(from: &File, to: &File) -> Result<(), io::Error> {
+    fn unsupported(e: &io::Error) -> bool {
+        e.raw_os_error() == Some(libc::ENOTSUP)
+    }
+
+    let size = unsafe { libc::flistxattr(from.as_raw_fd(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let e = io::Error::last_os_error();
+        return if unsupported(&e) { Ok(()) } else { Err(e) };
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe {
+        libc::flistxattr(from.as_raw_fd(), names.as_mut_ptr() as *mut libc::c_char, names.len())
+    };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    for name in names.split(|&b| b == 0).filter(|n| !n.is_empty()) {
+        let mut c_name = name.to_vec();
+        c_name.push(0);
+        let c_name = c_name.as_ptr() as *const libc::c_char;
+
+        let len = unsafe {
+            libc::fgetxattr(from.as_raw_fd(), c_name, std::ptr::null_mut(), 0)
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; len as usize];
+        let len = unsafe {
+            libc::fgetxattr(from.as_raw_fd(),
+                            c_name,
+                            value.as_mut_ptr() as *mut libc::c_void,
+                            value.len())
+        };
+        if len < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let res = unsafe {
+            libc::fsetxattr(to.as_raw_fd(),
+                            c_name,
+                            value.as_ptr() as *const libc::c_void,
+                            len as usize,
+                            0)
+        };
+        if res != 0 {
+            let e = io::Error::last_os_error();
+            if !unsupported(&e) {
+                return Err(e);
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn copy_xattrs(_from: &File, _to: &File) -> Result<(), io::Error> {
+    Ok(())
+}
+
+fn context(e: io::Error, what: &str) -> io::Error {
+    io::Error::new(e.kind(), format!("{}: {}", what, e))
+}
+
+
+///////////////
+// Schedules //
+///////////////
+// A schedule says when a domain is blocked, e.g. "09:00-17:00@weekdays".
+// Several windows are separated by ';' and the days default to every day:
+//
+//   09:00-12:00@mon-fri;13:00-17:00@mon-fri
+//   22:00-06:00             (overnight, runs into the next morning)
+//   00:00-00:00@sat,sun     (all day)
+//
+// Outside of its windows a scheduled domain is unblocked.
+fn parse_schedule(spec: &str) -> Result<Vec<Window>, String> {
+    let mut windows = Vec::new();
+    for part in spec.split(';').filter(|p| !p.is_empty()) {
+        let (times, days) = match part.find('@') {
+            Some(i) => (&part[..i], parse_days(&part[i + 1..])?),
+            None => (part, [true; 7]),
+        };
+        let mut clocks = times.splitn(2, '-');
+        let start = parse_clock(clocks.next().unwrap_or(""))?;
+        let end = match clocks.next() {
+            Some(clock) => parse_clock(clock)?,
+            None => return Err(format!("expected HH:MM-HH:MM, got \"{}\"", times)),
+        };
+        windows.push(Window {
+            days: days,
+            start: start,
+            end: end,
+        });
+    }
+
+    if windows.is_empty() {
+        return Err(format!("empty schedule \"{}\"", spec));
+    }
+    Ok(windows)
+}
+
+static DAY_NAMES: &'static [&'static str] = &["mon", "tue", "wed", "thu", "fri", "sat", "sun"];
+
+// "mon-fri", "sat,sun", "fri-mon" or one of daily, weekdays and weekends.
+fn parse_days(spec: &str) -> Result<[bool; 7], String> {
+    let day = |name: &str| {
+        DAY_NAMES.iter()
+                 .position(|d| *d == name)
+                 .ok_or_else(|| format!("unknown day \"{}\"", name))
+    };
+
+    let mut days = [false; 7];
+    for part in spec.split(',') {
+        match part {
+            "daily" => days = [true; 7],
+            "weekdays" => for d in &mut days[..5] { *d = true },
+            "weekends" => for d in &mut days[5..] { *d = true },
+            _ => {
+                let mut ends = part.splitn(2, '-');
+                let first = day(ends.next().unwrap_or(""))?;
+                let last = match ends.next() {
+                    Some(name) => day(name)?,
+                    None => first,
+                };
+                let mut d = first;
+                loop {
+                    days[d] = true;
+                    if d == last {
+                        break;
+                    }
+                    d = (d + 1) % 7;
+                }
+            }
+        }
+    }
+    Ok(days)
+}
+
+// "HH:MM" in minutes since midnight, "24:00" is allowed as an end.
+fn parse_clock(clock: &str) -> Result<u32, String> {
+    let invalid = || format!("expected a time like 09:30, got \"{}\"", clock);
+    let mut parts = clock.splitn(2, ':');
+    let hours = parts.next().and_then(|h| h.parse::<u32>().ok()).ok_or_else(&invalid)?;
+    let minutes = parts.next().and_then(|m| m.parse::<u32>().ok()).ok_or_else(&invalid)?;
+    if minutes > 59 || hours > 24 || (hours == 24 && minutes > 0) {
+        return Err(invalid());
+    }
+    Ok(hours * 60 + minutes)
+}
+
+// Weekday (Monday is 0) and minute of the day in local time.
+fn local_time(secs: u64) -> (usize, u32) {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    (((tm.tm_wday + 6) % 7) as usize, (tm.tm_hour * 60 + tm.tm_min) as u32)
+}
+
+fn in_window(windows: &[Window], weekday: usize, minute: u32) -> bool {
+    let yesterday = (weekday + 6) % 7;
+    windows.iter().any(|w| {
+        if w.start < w.end {
+            w.days[weekday] && w.start <= minute && minute < w.end
+        } else if w.start > w.end {
+            // The part before midnight belongs to the day it started on.
+            (w.days[weekday] && minute >= w.start) || (w.days[yesterday] && minute < w.end)
+        } else {
+            w.days[weekday]
+        }
+    })
+}
+
+// The domain's own schedule, or else its group's.
+fn schedule_for<'a>(domain: &'a Domain, groups: &'a [GroupSettings]) -> Option<&'a str> {
+    domain.schedule.as_ref().map(|s| s.as_str()).or_else(|| {
+        domain.group.as_ref().and_then(|name| {
+            groups.iter()
+                  .find(|g| &g.name == name)
+                  .and_then(|g| g.schedule.as_ref())
+                  .map(|s| s.as_str())
+        })
+    })
+}
+
+// Blocks and unblocks every scheduled domain according to the time `secs`.
+// Domains without a schedule, or with one that doesn't parse, are left
+// alone.  The schedules were set behind the passphrase, so following them
+// doesn't ask for it again.
+fn apply_schedules(state: &State, secs: u64) -> (Vec<String>, State) {
+    let (weekday, minute) = local_time(secs);
+    let mut new_state = state.clone();
+    let mut changes = Vec::new();
+
+    for domain in &mut new_state.domains {
+        // A temporary unblock runs its course first.
+        if domain.until.is_some() {
+            continue;
+        }
+        let blocked = match schedule_for(domain, &state.group_settings).map(parse_schedule) {
+            Some(Ok(windows)) => in_window(&windows, weekday, minute),
+            _ => continue,
+        };
+        let status = if blocked { DomainStatus::Blocked } else { DomainStatus::Unblocked };
+        if domain.status != status {
+            changes.push(format!("{} {}",
+                                 if blocked { "blocked" } else { "unblocked" },
+                                 domain.url));
+            domain.status = status;
+        }
+    }
+    (changes, new_state)
+}
+
+//////////////
+// Friction //
+//////////////
+// Every accepted passphrase is remembered as one unblock in <state dir>/unblocks,
+// a unix time per line, and the next unblock gets harder with each one:
+//
+//  - one more passphrase word per unblock in the last day, up to six more
+//  - 30 seconds of waiting per unblock in the last day, up to five minutes
+//  - a reason for the journal every time, and from the third unblock in a
+//    week on, typing it again once more for every other unblock in the last
+//    day, up to four more times
+fn friction_for(history: &[u64], secs: u64) -> Friction {
+    let since = |period: u64| history.iter().filter(|&&t| t + period > secs).count();
+    let (today, week) = (since(DAY), since(7 * DAY));
+
+    Friction {
+        extra_words: min(today, 6),
+        wait: min(today as u64 * 30, 300),
+        reasons: 1 + if week < 3 { 0 } else { min(1 + today / 2, 4) },
+    }
+}
+
+static DAY: u64 = 24 * 60 * 60;
+
+fn unblocks_path(config: &Config) -> PathBuf {
+    config.state_dir.join("unblocks")
+}
+
+// A missing or unreadable history just means no friction.
+fn load_unblocks(config: &Config) -> Vec<u64> {
+    fs::read(unblocks_path(config))
+        .map(|bytes| {
+            String::from_utf8_lossy(&bytes)
+                .lines()
+                .filter_map(|l| l.trim().parse().ok())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+// Adds `count` unblocks at the current time, dropping anything older than
+// the week friction_for looks at.
+fn record_unblocks(config: &Config, count: usize) -> Result<(), io::Error> {
+    let secs = now();
+    let mut history: Vec<u64> = load_unblocks(config)
+                                    .into_iter()
+                                    .filter(|&t| t + 7 * DAY > secs)
+                                    .collect();
+    history.extend(std::iter::repeat(secs).take(count));
+
+    let text: String = history.iter().map(|t| format!("{}\n", t)).collect();
+    fs::create_dir_all(&config.state_dir)
+        .map_err(|e| context(e, &format!("couldn't create {}", config.state_dir.display())))?;
+    write_atomically(&unblocks_path(config), text.as_bytes(), 0o600)
+}
+
+/////////////
+// Journal //
+/////////////
+// Every unblock gets a line in <state dir>/journal, which is only ever
+// appended to: unix time, domain, minutes for a temporary unblock or "-",
+// and the reason, separated by tabs.
+#[derive(Clone)]
+struct JournalEntry {
+    at: u64,
+    domain: String,
+    minutes: Option<u64>,
+    reason: String,
+}
+
+fn journal_path(config: &Config) -> PathBuf {
+    config.state_dir.join("journal")
+}
+
+fn render_journal_entry(entry: &JournalEntry) -> String {
+    let reason: String = entry.reason
+                              .chars()
+                              .map(|c| if c.is_control() { ' ' } else { c })
+                              .collect();
+    format!("{}\t{}\t{}\t{}\n",
+            entry.at,
+            entry.domain,
+            entry.minutes.map_or(String::from("-"), |m| m.to_string()),
+            reason)
+}
+
+fn parse_journal_entry(line: &str) -> Option<JournalEntry> {
+    let fields: Vec<&str> = line.splitn(4, '\t').collect();
+    if fields.len() != 4 {
+        return None;
+    }
+    Some(JournalEntry {
+        at: fields[0].parse().ok()?,
+        domain: String::from(fields[1]),
+        minutes: if fields[2] == "-" { None } else { Some(fields[2].parse().ok()?) },
+        reason: String::from(fields[3]),
+    })
+}
+
+fn load_journal(config: &Config) -> Result<Vec<JournalEntry>, io::Error> {
+    let bytes = match fs::read(journal_path(config)) {
+        Ok(bytes) => bytes,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(e) => return Err(e),
+    };
+    Ok(String::from_utf8_lossy(&bytes).lines().filter_map(parse_journal_entry).collect())
+}
+
+fn record_journal(config: &Config, entries: &[JournalEntry]) -> Result<(), io::Error> {
+    if entries.is_empty() {
+        return Ok(());
+    }
+    fs::create_dir_all(&config.state_dir)
+        .map_err(|e| context(e, &format!("couldn't create {}", config.state_dir.display())))?;
+    let text: String = entries.iter().map(render_journal_entry).collect();
+    fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .mode(0o600)
+        .open(journal_path(config))?
+        .write_all(text.as_bytes())
+}
+
+// The blocked domains this session unblocked or took off the list.
+fn unblocked_entries(state: &State, reason: &str, at: u64) -> Vec<JournalEntry> {
+    state.loaded
+         .iter()
+         .filter(|old| old.status == DomainStatus::Blocked)
+         .filter_map(|old| {
+             match state.domains.iter().find(|d| d.url == old.url) {
+                 Some(d) if d.status == DomainStatus::Blocked => None,
+                 Some(d) => Some((old, d.until)),
+                 None => Some((old, None)),
+             }
+         })
+         .map(|(old, until)| {
+             JournalEntry {
+                 at: at,
+                 domain: old.url.clone(),
+                 minutes: until.map(|u| (u.saturating_sub(at) + 59) / 60),
+                 reason: String::from(reason),
+             }
+         })
+         .collect()
+}
+
+////////////////
+// Anti-paste //
+////////////////
+// A challenge that can be pasted isn't one.  The TUI and the command line
+// turn on the terminal's bracketed paste, so a paste arrives wrapped in
+// ESC [200~ ... ESC [201~, and for terminals without it, FAST_KEYS keys in a
+// row less than MIN_KEY_GAP_MS apart are a paste or a script too.  Either way
+// the input is thrown away and there's a new challenge to type.
+// `--paste-check off` turns all of it off.
+static MIN_KEY_GAP_MS: u64 = 15;
+static FAST_KEYS: usize = 4;
+static PASTE_START: &'static str = "\x1b[200~";
+
+// Turns bracketed paste on for as long as it's alive.
+struct BracketedPaste {
+    on: bool,
+}
+
+impl BracketedPaste {
+    fn enable(on: bool) -> BracketedPaste {
+        if on {
+            print!("\x1b[?2004h");
+            io::stdout().flush().unwrap();
+        }
+        BracketedPaste { on: on }
+    }
+}
+
+impl Drop for BracketedPaste {
+    fn drop(&mut self) {
+        if self.on {
+            print!("\x1b[?2004l");
+            io::stdout().flush().unwrap();
+        }
+    }
+}
+
+// Puts the terminal in non-canonical mode without echo for as long as it's
+// alive, so the command line gets each key as it's typed.  None when stdin
+// isn't a terminal.
+struct RawInput {
+    saved: libc::termios,
+}
+
+impl RawInput {
+    fn enable() -> Option<RawInput> {
+        let mut saved: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(0, &mut saved) } != 0 {
+            return None;
+        }
+        let mut raw = saved;
+        // ISIG too: a ctrl-c that killed us here would leave the terminal
+        // without echo.
+        raw.c_lflag &= !(libc::ICANON | libc::ECHO | libc::ISIG);
+        raw.c_cc[libc::VMIN] = 1;
+        raw.c_cc[libc::VTIME] = 0;
+        if unsafe { libc::tcsetattr(0, libc::TCSANOW, &raw) } != 0 {
+            return None;
+        }
+        Some(RawInput { saved: saved })
+    }
+}
+
+impl Drop for RawInput {
+    fn drop(&mut self) {
+        unsafe { libc::tcsetattr(0, libc::TCSANOW, &self.saved) };
+    }
+}
+
+// The command line's answer to a challenge, read a key at a time and timed
+// like the TUI's.  None on ctrl-c or ctrl-d, otherwise the line and whether
+// it was pasted or typed by a script.
+fn read_typed() -> Option<(String, bool)> {
+    io::stdout().flush().unwrap();
+    let raw = RawInput::enable();
+    let mut line: Vec<u8> = Vec::new();
+    let (mut last_key_ms, mut fast_keys, mut too_fast) = (0, 0, false);
+    loop {
+        let mut byte = 0u8;
+        if unsafe { libc::read(0, &mut byte as *mut u8 as *mut libc::c_void, 1) } != 1 {
+            return None;
+        }
+        let at = now_millis();
+        if at.saturating_sub(last_key_ms) < MIN_KEY_GAP_MS {
+            fast_keys += 1;
+            too_fast = too_fast || fast_keys >= FAST_KEYS;
+        } else {
+            fast_keys = 0;
+        }
+        last_key_ms = at;
+
+        match byte {
+            b'\r' | b'\n' => break,
+            3 => return None,
+            4 if line.is_empty() => return None,
+            8 | 0x7f => {
+                // Back to the start of the last character.
+                while line.pop().map_or(false, |b| b & 0xc0 == 0x80) {}
+                if raw.is_some() {
+                    print!("\x08 \x08");
+                }
+            }
+            // Kept, but not echoed, so the paste markers can be found.
+            0x1b => line.push(byte),
+            b if b < 0x20 => {}
+            b => {
+                line.push(b);
+                if raw.is_some() {
+                    io::stdout().write_all(&[b]).unwrap();
+                }
+            }
+        }
+        io::stdout().flush().unwrap();
+    }
+    if raw.is_some() {
+        println!();
+    }
+
+    let line = String::from_utf8_lossy(&line).into_owned();
+    let pasted = too_fast || line.contains(PASTE_START);
+    Some((line.replace(PASTE_START, "").replace("\x1b[201~", ""), pasted))
+}
+
+fn typing_challenge(state: &State) -> bool {
+    match state.mode {
+        Mode::Password | Mode::Unlock => state.paste_check,
+        _ => false,
+    }
+}
+
+// Termbox doesn't know the paste markers, so they come in as an Esc followed
+// right away by "[200~".  Called after that Esc, true if it was a paste, in
+// which case the pasted text is read and dropped.
+fn read_paste(rustbox: &RustBox) -> bool {
+    for expected in PASTE_START.chars().skip(1) {
+        match rustbox.peek_event(Duration::from_millis(MIN_KEY_GAP_MS), false) {
+            Ok(rustbox::Event::KeyEvent(Key::Char(c))) if c == expected => {}
+            _ => return false,
+        }
+    }
+    loop {
+        match rustbox.peek_event(Duration::from_millis(100), false) {
+            Ok(rustbox::Event::KeyEvent(_)) => {}
+            _ => return true,
+        }
+    }
+}
+
+fn reject_paste(state: &State) -> State {
+    let mut new_state = state.clone();
+    new_state.challenge = new_challenge(state, &state.mode);
+    new_state.pass_input = String::new();
+    new_state.last_key_ms = 0;
+    new_state.fast_keys = 0;
+    new_state.pasted = true;
+    new_state
+}
+
+fn now_millis() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs() * 1000 + d.subsec_nanos() as u64 / 1000000,
+        Err(_) => 0,
+    }
+}
+
+////////////////
+// Challenges //
+////////////////
+// What has to be typed to get past the gate.  The kind is set with
+// --challenge and per group with `hostblock challenge`.
+trait Challenge {
+    // `size` is how many passphrase words the unblock is worth, the other
+    // kinds scale from that.
+    fn generate(&self, size: usize) -> Puzzle;
+}
+
+struct Words {
+    list: Vec<String>,
+}
+
+// A long random string, without the characters that look alike.
+struct CopyString;
+
+struct Arithmetic;
+
+// The same paragraph every time, typed without a mistake, growing a sentence
+// for every two words the unblock is worth beyond a single domain's two.
+struct Paragraph;
+
+static WORDS: &'static [&'static str] = &["dilate", "indict", "sacrilegious", "ingenious",
+                                          "minuscule", "onomatopoeia", "accommodate",
+                                          "conscientious", "Wednesday", "acquiesce", "bologna",
+                                          "fuchsia", "nauseous", "orangutan", "paraphernalia"];
+
+static PARAGRAPH: &'static str = "I am about to unblock a site I decided to block when I was \
+                                  thinking clearly. Nothing on it is going to be different \
+                                  from the last time I looked, and whatever I was working on \
+                                  will still be waiting when I come back, only later.";
+static PARAGRAPH_MORE: &'static [&'static str] =
+    &["I have already unblocked things today, and every time it felt necessary.",
+      "None of those times made the rest of the day go any better.",
+      "If this is really important it can wait until I have finished what I started.",
+      "The version of me that set up this block knew this moment would come.",
+      "I am choosing to type all of this instead of getting back to work."];
+
+impl Challenge for Words {
+    fn generate(&self, size: usize) -> Puzzle {
+        let mut words = self.list.clone();
+        rand::thread_rng().shuffle(&mut words);
+        words.truncate(size);
+        let pass = words.join(" ");
+        Puzzle {
+            title: String::from("Type the passphrase below to save"),
+            prompt: pass.clone(),
+            answer: pass,
+        }
+    }
+}
+
+impl Challenge for CopyString {
+    fn generate(&self, size: usize) -> Puzzle {
+        let chars: Vec<char> = "abcdefghijkmnpqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ23456789"
+                                   .chars()
+                                   .collect();
+        let mut rng = rand::thread_rng();
+        let s: String = (0..size * 8).map(|_| *rng.choose(&chars).unwrap()).collect();
+        Puzzle {
+            title: String::from("Copy the string below to save"),
+            prompt: s.clone(),
+            answer: s,
+        }
+    }
+}
+
+impl Challenge for Arithmetic {
+    fn generate(&self, size: usize) -> Puzzle {
+        let mut rng = rand::thread_rng();
+        let first = rng.gen_range(2, 50);
+        let mut prompt = first.to_string();
+        // The sum so far, and the product being built, for * before +.  At
+        // most two factors to a product, keeping the answer something that
+        // can be worked out and typed.
+        let (mut total, mut term): (i64, i64) = (0, first);
+        let mut factors = 1;
+        for _ in 0..size + 1 {
+            let n = rng.gen_range(2, 50);
+            let op = if factors < 2 { rng.gen_range(0, 3) } else { rng.gen_range(0, 2) };
+            factors = if op == 2 { factors + 1 } else { 1 };
+            match op {
+                0 => {
+                    total += term;
+                    term = n;
+                    prompt.push_str(&format!(" + {}", n));
+                }
+                1 => {
+                    total += term;
+                    term = -n;
+                    prompt.push_str(&format!(" - {}", n));
+                }
+                _ => {
+                    term *= n;
+                    prompt.push_str(&format!(" * {}", n));
+                }
+            }
+        }
+        Puzzle {
+            title: String::from("Solve this to save"),
+            prompt: prompt + " =",
+            answer: (total + term).to_string(),
+        }
+    }
+}
+
+impl Challenge for Paragraph {
+    fn generate(&self, size: usize) -> Puzzle {
+        let mut paragraph = String::from(PARAGRAPH);
+        for sentence in PARAGRAPH_MORE.iter().take(size.saturating_sub(2) / 2) {
+            paragraph.push(' ');
+            paragraph.push_str(sentence);
+        }
+        Puzzle {
+            title: String::from("Type the paragraph below to save"),
+            prompt: paragraph.clone(),
+            answer: paragraph,
+        }
+    }
+}
+
+fn challenge_for(kind: &str, config: &ChallengeConfig) -> Box<dyn Challenge> {
+    match kind {
+        "string" => Box::new(CopyString),
+        "arithmetic" => Box::new(Arithmetic),
+        "paragraph" => Box::new(Paragraph),
+        _ => {
+            let list = config.wordlist.as_ref().and_then(|path| read_wordlist(path).ok());
+            Box::new(Words {
+                list: list.unwrap_or_else(|| WORDS.iter().map(|w| String::from(*w)).collect()),
+            })
+        }
+    }
+}
+
+fn group_challenge<'a>(domain: &Domain, groups: &'a [GroupSettings]) -> Option<&'a str> {
+    domain.group.as_ref().and_then(|name| {
+        groups.iter()
+              .find(|g| &g.name == name)
+              .and_then(|g| g.challenge.as_ref())
+              .map(|c| c.as_str())
+    })
+}
+
+// The group's own kind of challenge, or the configured one.
+fn make_challenge(config: &ChallengeConfig,
+                  groups: &[GroupSettings],
+                  group: Option<&str>,
+                  size: usize)
+                  -> Puzzle {
+    let kind = group.and_then(|name| groups.iter().find(|g| g.name == name))
+                    .and_then(|g| g.challenge.as_ref())
+                    .filter(|kind| CHALLENGE_KINDS.contains(&kind.as_str()))
+                    .unwrap_or(&config.default);
+    challenge_for(kind, config).generate(size)
+}
+
+// Words of letters only, so they can be typed on any keyboard layout.
+fn read_wordlist(path: &Path) -> Result<Vec<String>, io::Error> {
+    let bytes = fs::read(path)?;
+    let words: Vec<String> = String::from_utf8_lossy(&bytes)
+                                 .lines()
+                                 .map(|l| l.trim())
+                                 .filter(|w| !w.is_empty() && w.chars().all(|c| c.is_alphabetic()))
+                                 .map(String::from)
+                                 .collect();
+    if words.len() < 12 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData,
+                                  format!("{} has fewer than 12 usable words", path.display())));
+    }
+    Ok(words)
+}
+
+// The group whose challenge guards this session's changes: one that had a
+// blocked domain unblocked, removed, redirected or moved to another group, or
+// its settings changed.
+// Groups with a challenge of their own win.
+fn unblocked_group(state: &State) -> Option<String> {
+    let mut touched: Vec<&String> = state.loaded
+                                         .iter()
+                                         .filter(|old| old.status == DomainStatus::Blocked)
+                                         .filter(|old| {
+                                             state.domains.iter().find(|d| d.url == old.url).map_or(true, |d| {
+                                                 d.status == DomainStatus::Unblocked ||
+                                                 d.sink != old.sink ||
+                                                 d.group != old.group
+                                             })
+                                         })
+                                         .filter_map(|old| old.group.as_ref())
+                                         .collect();
+    for old in &state.loaded_groups {
+        let changed = state.group_settings.iter().find(|g| g.name == old.name).map_or(true, |g| {
+            g.schedule != old.schedule || g.challenge != old.challenge
+        });
+        if changed {
+            touched.push(&old.name);
+        }
+    }
+    touched.sort();
+
+    let own_challenge = |name: &&&String| {
+        state.loaded_groups.iter().any(|g| g.name == ***name && g.challenge.is_some())
+    };
+    touched.iter().find(own_challenge).or_else(|| touched.first()).map(|g| (*g).clone())
+}
+
+////////////
+// Daemon //
+////////////
+// `hostblock daemon` keeps the managed section the way hostblock last wrote
+// it.  Anything else that touches the section, `sudo vim /etc/hosts` or
+// deleting it outright, is reverted and logged; edits to the rest of the file
+// are left alone.  Once a minute it also expires temporary unblocks and
+// follows schedules, so cron isn't needed next to it.
+fn daemon_command(config: &Config) -> i32 {
+    let dir = match config.hosts_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    // The directory rather than the file, editors and hostblock itself
+    // replace the file instead of writing to it.
+    let watch = match watch_dir(&dir) {
+        Ok(fd) => fd,
+        Err(e) => {
+            writeln!(&mut std::io::stderr(), "Couldn't watch {}: {}", dir.display(), e).unwrap();
+            return 1;
+        }
+    };
+
+    // Without a record hostblock hasn't saved since the daemon existed, so
+    // the file as it is now is the best guess.
+    if !config.section_path().exists() {
+        let current = parse_hosts(&read_hosts(&config.hosts_path));
+        if let Err(e) = record_section(config, &render_section(&current)) {
+            writeln!(&mut std::io::stderr(), "Couldn't record the section: {}", e).unwrap();
+            return 1;
+        }
+        log(&format!("recorded the current section of {}", config.hosts_path.display()));
+    }
+
+    log(&format!("watching {}", config.hosts_path.display()));
+    enforce_section(config);
+    let mut last_tick = now();
+    loop {
+        match wait_for_change(watch, 60 * 1000) {
+            Ok(true) => {
+                // Let whoever is writing finish before looking.
+                thread::sleep(Duration::from_millis(100));
+                enforce_section(config);
+            }
+            Ok(false) => {}
+            Err(e) => {
+                log(&format!("couldn't watch {}: {}", dir.display(), e));
+                return 1;
+            }
+        }
+        if now() >= last_tick + 60 {
+            last_tick = now();
+            enforce_timers(config);
+        }
+    }
+}
+
+// Puts the recorded section back if the file's differs.
+fn enforce_section(config: &Config) {
+    let expected = match fs::read(config.section_path()) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(e) => {
+            log(&format!("couldn't read {}: {}", config.section_path().display(), e));
+            return;
+        }
+    };
+    let current_text = match fs::read(&config.hosts_path) {
+        Ok(bytes) => String::from_utf8_lossy(&bytes).into_owned(),
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(e) => {
+            log(&format!("couldn't read {}: {}", config.hosts_path.display(), e));
+            return;
+        }
+    };
+    let mut hosts = parse_hosts(&current_text);
+    let current = render_section(&hosts);
+    if current == expected {
+        return;
+    }
+
+    let old: Vec<&str> = current.lines().collect();
+    let new: Vec<&str> = expected.lines().collect();
+    for line in diff_lines(&old, &new) {
+        match line {
+            DiffLine::Removed(l) => log(&format!("reverting edit: -{}", l)),
+            DiffLine::Added(l) => log(&format!("reverting edit: +{}", l)),
+            DiffLine::Same(_) => {}
+        }
+    }
+
+    let recorded = parse_hosts(&expected);
+    hosts.header = recorded.header;
+    hosts.groups = recorded.groups;
+    hosts.domains = recorded.domains;
+    hosts.trailing = recorded.trailing;
+    hosts.footer = recorded.footer;
+    // A deleted file comes back as just the section.
+    if current_text.is_empty() {
+        hosts.ends_with_newline = true;
+    }
+    match write_atomically(&config.hosts_path, render_hosts(&hosts).as_bytes(), HOSTS_MODE) {
+        Ok(_) => log(&format!("restored the section of {}", config.hosts_path.display())),
+        Err(e) => log(&format!("couldn't restore {}: {}", config.hosts_path.display(), e)),
+    }
+}
+
+fn enforce_timers(config: &Config) {
+    let state = match load_state(config) {
+        Ok(state) => state,
+        Err(e) => return log(&format!("couldn't read {}: {}", config.hosts_path.display(), e)),
+    };
+    let (expired, state) = expire_unblocks(&state, now());
+    let (scheduled, state) = apply_schedules(&state, now());
+    if expired.is_empty() && scheduled.is_empty() {
+        return;
+    }
+    match save_hosts(config, &state) {
+        Ok(_) => {
+            for line in expired.iter().chain(&scheduled) {
+                log(line);
+            }
+        }
+        Err(e) => log(&format!("couldn't save {}: {}", config.hosts_path.display(), e)),
+    }
+}
+
+fn log(msg: &str) {
+    writeln!(&mut std::io::stderr(), "{} {}", format_time(now()), msg).unwrap();
+}
+
+#[cfg(target_os = "linux")]
+fn watch_dir(dir: &Path) -> Result<libc::c_int, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let path = CString::new(dir.as_os_str().as_bytes())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "path contains a NUL byte"))?;
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mask = libc::IN_CLOSE_WRITE | libc::IN_MOVED_TO | libc::IN_MOVED_FROM |
+               libc::IN_CREATE | libc::IN_DELETE | libc::IN_DELETE_SELF;
+    if unsafe { libc::inotify_add_watch(fd, path.as_ptr(), mask) } < 0 {
+        let e = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+// true when something changed in the directory, false on timeout.  Which
+// file it was doesn't matter, checking the hosts file is cheap.
+#[cfg(target_os = "linux")]
+fn wait_for_change(fd: libc::c_int, timeout_ms: i32) -> Result<bool, io::Error> {
+    let mut poll_fd = libc::pollfd {
+        fd: fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    match unsafe { libc::poll(&mut poll_fd, 1, timeout_ms) } {
+        0 => return Ok(false),
+        n if n < 0 => {
+            let e = io::Error::last_os_error();
+            return if e.kind() == io::ErrorKind::Interrupted { Ok(false) } else { Err(e) };
+        }
+        _ => {}
+    }
+
+    let mut buf = [0u8; 4096];
+    if unsafe { libc::read(fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(true)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn watch_dir(_dir: &Path) -> Result<libc::c_int, io::Error> {
+    Err(io::Error::new(io::ErrorKind::Other, "the daemon needs inotify, which is Linux only"))
+}
+
+#[cfg(not(target_os = "linux"))]
+fn wait_for_change(_fd: libc::c_int, _timeout_ms: i32) -> Result<bool, io::Error> {
+    Ok(false)
+}
+
+////////////
+// Server //
+////////////
+// `hostblock serve` answers one JSON object per line on two Unix sockets, so
+// widgets and editor plugins can drive hostblock without root:
+//
+//   {"cmd":"list"}                          {"ok":true,"domains":[...]}
+//   {"cmd":"status"}                        {"ok":true,"blocked":3,...}
+//   {"cmd":"add","domain":"x.com","group":"news"}
+//   {"cmd":"block","domain":"x.com"}        or "group" instead of "domain"
+//   {"cmd":"subscribe"}                     then {"event":"changed",...}
+//
+// and only on the unblock socket, a request that hands out the passphrase
+// followed by the unblock that has to repeat it:
+//
+//   {"cmd":"unblock-request","domain":"x.com","minutes":10}
+//                                           {"ok":true,"id":1,"challenge":"..."}
+//   {"cmd":"unblock","id":1,"passphrase":"..."}
+//                                           {"ok":true,"cooldown":60}
+//   {"cmd":"unblock","id":1}                once the cooldown is over, or
+//   {"cmd":"cancel"}
+//
+// Failures come back as {"ok":false,"error":"..."}.
+struct Server {
+    config: Config,
+    // One client's read-modify-write of the hosts file at a time.
+    lock: Mutex<()>,
+    subscribers: Mutex<Vec<UnixStream>>,
+    clients: Mutex<usize>,
+}
+
+// An unblock waiting for its passphrase, one per connection.
+struct UnblockRequest {
+    id: u64,
+    domains: Vec<String>,
+    // Seconds a temporary unblock lasts, counted from when it happens.
+    lasts: Option<u64>,
+    challenge: Puzzle,
+    // The friction wait, the passphrase isn't accepted before then.
+    not_before: u64,
+    needs_reason: bool,
+    // Set once the passphrase is right, the end of the cooldown.
+    ready_at: Option<u64>,
+}
+
+fn serve_command(config: &Config) -> i32 {
+    let server = Arc::new(Server {
+        config: config.clone(),
+        lock: Mutex::new(()),
+        subscribers: Mutex::new(Vec::new()),
+        clients: Mutex::new(0),
+    });
+
+    // Only directories made here get a mode, an existing one like /run is
+    // left as it is.
+    let listeners = fs::DirBuilder::new()
+        .recursive(true)
+        .mode(0o755)
+        .create(&config.socket_dir)
+        .map_err(|e| context(e, &format!("couldn't create {}", config.socket_dir.display())))
+        .and_then(|_| bind_socket(&config.socket_dir.join(CONTROL_SOCKET), 0o666, None))
+        .and_then(|control| {
+            bind_socket(&config.socket_dir.join(UNBLOCK_SOCKET),
+                        0o660,
+                        config.unblock_group.as_ref().map(|g| g.as_str()))
+                .map(|unblock| (control, unblock))
+        });
+    let (control, unblock) = match listeners {
+        Ok(listeners) => listeners,
+        Err(e) => {
+            writeln!(&mut std::io::stderr(), "Couldn't start serving: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    {
+        let server = server.clone();
+        thread::spawn(move || notify_subscribers(&server));
+    }
+    {
+        let server = server.clone();
+        thread::spawn(move || accept_clients(&server, control, false));
+    }
+    log(&format!("serving {} on {}", config.hosts_path.display(), config.socket_dir.display()));
+    accept_clients(&server, unblock, true);
+    1
+}
+
+fn bind_socket(path: &Path, mode: u32, group: Option<&str>) -> Result<UnixListener, io::Error> {
+    use std::ffi::CString;
+    use std::os::unix::ffi::OsStrExt;
+
+    let in_path = |e: io::Error| context(e, &format!("couldn't bind {}", path.display()));
+
+    // A socket nobody answers on is left over from a crash.
+    if UnixStream::connect(path).is_ok() {
+        return Err(io::Error::new(io::ErrorKind::AddrInUse,
+                                  format!("something is already serving on {}", path.display())));
+    }
+    if let Err(e) = fs::remove_file(path) {
+        if e.kind() != io::ErrorKind::NotFound {
+            return Err(in_path(e));
+        }
+    }
+
+    let listener = UnixListener::bind(path).map_err(&in_path)?;
+    if let Some(group) = group {
+        let name = CString::new(group)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad group name"))?;
+        let path_c = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "bad socket path"))?;
+        let grp = unsafe { libc::getgrnam(name.as_ptr()) };
+        if grp.is_null() {
+            return Err(io::Error::new(io::ErrorKind::NotFound,
+                                      format!("no group named {}", group)));
+        }
+        let gid = unsafe { (*grp).gr_gid };
+        if unsafe { libc::chown(path_c.as_ptr(), !0, gid) } != 0 {
+            return Err(in_path(io::Error::last_os_error()));
+        }
+    }
+    fs::set_permissions(path, fs::Permissions::from_mode(mode)).map_err(&in_path)?;
+    Ok(listener)
+}
+
+fn accept_clients(server: &Arc<Server>, listener: UnixListener, can_unblock: bool) {
+    for stream in listener.incoming() {
+        match stream {
+            Ok(mut stream) => {
+                {
+                    let mut clients = server.clients.lock().unwrap();
+                    if *clients >= MAX_CLIENTS {
+                        let _ = writeln!(stream, "{}", json_error("too many clients"));
+                        continue;
+                    }
+                    *clients += 1;
+                }
+                let server = server.clone();
+                thread::spawn(move || {
+                    serve_client(&server, stream, can_unblock);
+                    *server.clients.lock().unwrap() -= 1;
+                });
+            }
+            Err(e) => log(&format!("couldn't accept a client: {}", e)),
+        }
+    }
+}
+
+// The next request, None once the client is gone.  One longer than
+// MAX_REQUEST is an error.
+fn read_request<R: BufRead>(reader: &mut R) -> Option<Result<String, String>> {
+    let mut line = Vec::new();
+    match reader.take(MAX_REQUEST as u64 + 1).read_until(b'\n', &mut line) {
+        Ok(0) | Err(_) => None,
+        Ok(_) if line.len() > MAX_REQUEST => {
+            Some(Err(format!("requests are limited to {} bytes", MAX_REQUEST)))
+        }
+        Ok(_) => Some(Ok(String::from_utf8_lossy(&line).into_owned())),
+    }
+}
+
+fn serve_client(server: &Server, stream: UnixStream, can_unblock: bool) {
+    let mut writer = match stream.try_clone() {
+        Ok(writer) => writer,
+        Err(_) => return,
+    };
+    let mut pending = None;
+    let mut next_id = 1;
+
+    let mut reader = BufReader::new(stream);
+    while let Some(line) = read_request(&mut reader) {
+        let line = match line {
+            Ok(line) => line,
+            Err(e) => {
+                let _ = writeln!(writer, "{}", json_error(&e));
+                return;
+            }
+        };
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let response = match parse_json_object(&line) {
+            Ok(ref req) if req.get("cmd").map(|c| c.as_str()) == Some("subscribe") => {
+                let subscriber = writer.try_clone().and_then(|s| {
+                    s.set_write_timeout(Some(Duration::from_millis(SUBSCRIBER_TIMEOUT_MS)))
+                     .map(|_| s)
+                });
+                match subscriber {
+                    Ok(subscriber) => {
+                        server.subscribers.lock().unwrap().push(subscriber);
+                        String::from("{\"ok\":true}")
+                    }
+                    Err(e) => json_error(&e.to_string()),
+                }
+            }
+            Ok(req) => {
+                let _guard = server.lock.lock().unwrap();
+                handle_request(&server.config, &req, can_unblock, &mut pending, &mut next_id)
+            }
+            Err(e) => json_error(&format!("invalid request: {}", e)),
+        };
+        if writeln!(writer, "{}", response).is_err() {
+            return;
+        }
+    }
+}
+
+fn handle_request(config: &Config,
+                  req: &HashMap<String, String>,
+                  can_unblock: bool,
+                  pending: &mut Option<UnblockRequest>,
+                  next_id: &mut u64)
+                  -> String {
+    let state = match load_state(config) {
+        Ok(state) => state,
+        Err(e) => return json_error(&format!("couldn't read the hosts file: {}", e)),
+    };
+    let domain = req.get("domain").map(|d| d.as_str());
+    let group = req.get("group").map(|g| g.as_str());
+    let position = |url: &str| state.domains.iter().position(|d| d.url == url);
+
+    let new_state = match req.get("cmd").map(|c| c.as_str()) {
+        Some("list") => {
+            let entries: Vec<String> = state.domains
+                                            .iter()
+                                            .map(|d| domain_json(d, &state.group_settings))
+                                            .collect();
+            return format!("{{\"ok\":true,\"domains\":[{}]}}", entries.join(","));
+        }
+        Some("status") => return format!("{{\"ok\":true,{}}}", status_fields(&state)),
+        Some("add") => {
+            match domain {
+                Some(url) if !valid_domain(url) => return json_error("not a domain"),
+                Some(url) if position(url).is_some() => {
+                    return json_error("already on the list")
+                }
+                Some(_) if group.map_or(false, |g| !valid_group(g)) => {
+                    return json_error("not a group name")
+                }
+                Some(url) => {
+                    let mut new_state = add_url(&state, url);
+                    new_state.selected = new_state.domains.len() - 1;
+                    set_group(&new_state, group.unwrap_or(""))
+                }
+                None => return json_error("add needs a domain"),
+            }
+        }
+        Some("block") => {
+            match (domain, group) {
+                (Some(url), _) => {
+                    match position(url) {
+                        Some(i) if state.domains[i].status == DomainStatus::Unblocked => {
+                            let mut selected = state.clone();
+                            selected.selected = i;
+                            toggle_block(&selected)
+                        }
+                        Some(_) => state.clone(),
+                        None => return json_error("not on the list"),
+                    }
+                }
+                (None, Some(group)) => block_all(state.clone(), Some(group)),
+                (None, None) => return json_error("block needs a domain or a group"),
+            }
+        }
+        Some("unblock-request") if can_unblock => {
+            let domains: Vec<String> = match (domain, group) {
+                (Some(url), _) if position(url).is_some() => vec![String::from(url)],
+                (Some(_), _) => return json_error("not on the list"),
+                (None, Some(group)) => {
+                    state.domains
+                         .iter()
+                         .filter(|d| in_group(d, Some(group)))
+                         .map(|d| d.url.clone())
+                         .collect()
+                }
+                (None, None) => return json_error("unblock-request needs a domain or a group"),
+            };
+            if domains.is_empty() {
+                return json_error("no domains in that group");
+            }
+            let lasts = match req.get("minutes") {
+                Some(m) => {
+                    match parse_duration(m) {
+                        Some(secs) => Some(secs),
+                        None => {
+                            return json_error("minutes must be a positive number, up to a week")
+                        }
+                    }
+                }
+                None => None,
+            };
+            // Same price as the interface: a group costs the long passphrase.
+            let friction = &state.friction;
+            let challenge_group = match domain {
+                Some(url) => position(url).and_then(|i| state.domains[i].group.clone()),
+                None => group.map(String::from),
+            };
+            let challenge = make_challenge(&config.challenges,
+                                           &state.group_settings,
+                                           challenge_group.as_ref().map(|g| g.as_str()),
+                                           if domain.is_some() { 2 } else { 6 } +
+                                           friction.extra_words);
+            let response = format!("{{\"ok\":true,\"id\":{},\"title\":{},\"challenge\":{},\
+                                    \"wait\":{},\"reason\":{}}}",
+                                   next_id,
+                                   json_str(&challenge.title),
+                                   json_str(&challenge.prompt),
+                                   friction.wait,
+                                   friction.reasons > 0);
+            *pending = Some(UnblockRequest {
+                id: *next_id,
+                domains: domains,
+                lasts: lasts,
+                challenge: challenge,
+                not_before: now().saturating_add(friction.wait),
+                needs_reason: friction.reasons > 0,
+                ready_at: None,
+            });
+            *next_id += 1;
+            return response;
+        }
+        Some("unblock") if can_unblock => {
+            let mut request = match pending.take() {
+                Some(ref request) if req.get("id") != Some(&request.id.to_string()) => {
+                    return json_error("no such unblock request")
+                }
+                Some(request) => request,
+                None => return json_error("send an unblock-request first"),
+            };
+            // The passphrase was already right, this is the unblock after the
+            // cooldown.
+            if let Some(ready_at) = request.ready_at {
+                if now() < ready_at {
+                    *pending = Some(request);
+                    return json_error(&format!("cooling down for {} more seconds",
+                                               ready_at - now()));
+                }
+            }
+            let error = if request.ready_at.is_some() {
+                None
+            } else if now() < request.not_before {
+                Some(format!("wait {} more seconds", request.not_before - now()))
+            } else if request.needs_reason &&
+                      req.get("reason").map_or(true, |r| r.trim().is_empty()) {
+                Some(String::from("this unblock needs a reason"))
+            } else if req.get("passphrase") != Some(&request.challenge.answer) {
+                Some(String::from("wrong passphrase"))
+            } else {
+                None
+            };
+            if let Some(error) = error {
+                *pending = Some(request);
+                return json_error(&error);
+            }
+            if request.ready_at.is_none() && config.cooldown > 0 {
+                request.ready_at = Some(now().saturating_add(config.cooldown));
+                *pending = Some(request);
+                return format!("{{\"ok\":true,\"cooldown\":{}}}", config.cooldown);
+            }
+
+            let mut new_state = state.clone();
+            for domain in &mut new_state.domains {
+                if request.domains.contains(&domain.url) {
+                    domain.status = DomainStatus::Unblocked;
+                    domain.until = request.lasts.map(|secs| now().saturating_add(secs));
+                }
+            }
+            new_state
+        }
+        Some("cancel") => {
+            *pending = None;
+            return String::from("{\"ok\":true}");
+        }
+        Some("unblock-request") | Some("unblock") => {
+            return json_error("unblocking is only allowed on the unblock socket")
+        }
+        Some(other) => return json_error(&format!("unknown command {}", other)),
+        None => return json_error("missing \"cmd\""),
+    };
+
+    match save_hosts(config, &new_state) {
+        Ok(_) => {
+            if req.get("cmd").map(|c| c.as_str()) == Some("unblock") {
+                if let Err(e) = record_unblocks(config, 1) {
+                    log(&format!("couldn't record the unblock: {}", e));
+                }
+                let reason = req.get("reason").map_or("", |r| r.as_str());
+                if let Err(e) = record_journal(config, &unblocked_entries(&new_state, reason, now())) {
+                    log(&format!("couldn't write the journal: {}", e));
+                }
+            }
+            String::from("{\"ok\":true}")
+        }
+        Err(e) => json_error(&format!("couldn't save: {}", e)),
+    }
+}
+
+// Tells subscribers about every change to the section, whether it came
+// through the socket, the command line or the interface.
+fn notify_subscribers(server: &Server) {
+    let config = &server.config;
+    let dir = match config.hosts_path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    let watch = match watch_dir(&dir) {
+        Ok(fd) => fd,
+        Err(e) => return log(&format!("couldn't watch {}, no change events: {}", dir.display(), e)),
+    };
+
+    let section = |state: &Result<State, io::Error>| {
+        state.as_ref().ok().map(|s| s.domains.iter().map(render_entry).collect::<Vec<_>>())
+    };
+    let mut last = section(&load_state(config));
+    loop {
+        match wait_for_change(watch, -1) {
+            Ok(true) => {}
+            Ok(false) => continue,
+            Err(e) => return log(&format!("couldn't watch {}: {}", dir.display(), e)),
+        }
+        let state = load_state(config);
+        let current = section(&state);
+        if current == last {
+            continue;
+        }
+        last = current;
+        if let Ok(state) = state {
+            let event = format!("{{\"event\":\"changed\",{}}}", status_fields(&state));
+            // Written without the lock, so a slow subscriber only holds up
+            // this loop and never a new `subscribe`.
+            let mut subscribers = std::mem::replace(&mut *server.subscribers.lock().unwrap(),
+                                                    Vec::new());
+            subscribers.retain(|mut s| writeln!(s, "{}", event).is_ok());
+            server.subscribers.lock().unwrap().extend(subscribers);
+        }
+    }
+}
+
+fn json_error(msg: &str) -> String {
+    format!("{{\"ok\":false,\"error\":{}}}", json_str(msg))
+}
+
+// Just enough JSON for the requests: one flat object, with strings, numbers
+// and booleans all handed back as strings and nulls left out.
+fn parse_json_object(text: &str) -> Result<HashMap<String, String>, String> {
+    let mut chars = text.trim().chars().peekable();
+    let mut object = HashMap::new();
+    if chars.next() != Some('{') {
+        return Err(String::from("expected an object"));
+    }
+
+    let mut first = true;
+    loop {
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some('}') if first => break,
+            Some('"') => {}
+            _ => return Err(String::from("expected a key")),
+        }
+        first = false;
+        let key = parse_json_string(&mut chars)?;
+        skip_whitespace(&mut chars);
+        if chars.next() != Some(':') {
+            return Err(format!("expected ':' after \"{}\"", key));
+        }
+        skip_whitespace(&mut chars);
+        let value = if chars.peek() == Some(&'"') {
+            chars.next();
+            Some(parse_json_string(&mut chars)?)
+        } else {
+            let mut word = String::new();
+            while let Some(&c) = chars.peek() {
+                if c == ',' || c == '}' || c.is_whitespace() {
+                    break;
+                }
+                word.push(c);
+                chars.next();
+            }
+            if word == "null" {
+                None
+            } else if word == "true" || word == "false" || word.parse::<f64>().is_ok() {
+                Some(word)
+            } else {
+                return Err(format!("unexpected value for \"{}\"", key));
+            }
+        };
+        if let Some(value) = value {
+            object.insert(key, value);
+        }
+        skip_whitespace(&mut chars);
+        match chars.next() {
+            Some(',') => {}
+            Some('}') => break,
+            _ => return Err(String::from("expected ',' or '}'")),
+        }
+    }
+
+    skip_whitespace(&mut chars);
+    if chars.next().is_some() {
+        return Err(String::from("trailing characters after the object"));
+    }
+    Ok(object)
+}
+
+fn skip_whitespace<I: Iterator<Item = char>>(chars: &mut std::iter::Peekable<I>) {
+    while chars.peek().map_or(false, |c| c.is_whitespace()) {
+        chars.next();
+    }
+}
+
+// The four hex digits after \u.
+fn parse_json_hex<I: Iterator<Item = char>>(chars: &mut I) -> Result<u32, String> {
+    let hex: String = chars.by_ref().take(4).collect();
+    if hex.len() != 4 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(format!("bad \\u escape \"{}\"", hex));
+    }
+    Ok(u32::from_str_radix(&hex, 16).unwrap())
+}
+
+// The rest of a string after its opening quote.
+fn parse_json_string<I: Iterator<Item = char>>(chars: &mut I) -> Result<String, String> {
+    let mut s = String::new();
+    loop {
+        match chars.next() {
+            Some('"') => return Ok(s),
+            Some('\\') => {
+                match chars.next() {
+                    Some('n') => s.push('\n'),
+                    Some('r') => s.push('\r'),
+                    Some('t') => s.push('\t'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let mut code = parse_json_hex(chars)?;
+                        // Outside the BMP it takes a surrogate pair.
+                        if code >= 0xd800 && code < 0xdc00 {
+                            if chars.next() != Some('\\') || chars.next() != Some('u') {
+                                return Err(String::from("unpaired surrogate in \\u escape"));
+                            }
+                            let low = parse_json_hex(chars)?;
+                            if low < 0xdc00 || low >= 0xe000 {
+                                return Err(String::from("unpaired surrogate in \\u escape"));
+                            }
+                            code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                        }
+                        match std::char::from_u32(code) {
+                            Some(c) => s.push(c),
+                            None => return Err(String::from("unpaired surrogate in \\u escape")),
+                        }
+                    }
+                    Some(c @ '"') | Some(c @ '\\') | Some(c @ '/') => s.push(c),
+                    Some(c) => return Err(format!("unknown escape \\{}", c)),
+                    None => return Err(String::from("unterminated string")),
+                }
+            }
+            Some(c) => s.push(c),
+            None => return Err(String::from("unterminated string")),
+        }
+    }
+}
+
+/////////////
+// Backups //
+/////////////
+fn list_backups(config: &Config) -> Result<Vec<Backup>, io::Error> {
+    let prefix = config.backup_prefix();
+    let mut backups = Vec::new();
+
+    for entry in fs::read_dir(&config.backup_dir)? {
+        let entry = entry?;
+        let name = entry.file_name().to_string_lossy().into_owned();
+        if !name.starts_with(&prefix) {
+            continue;
+        }
+        if let Ok(id) = name[prefix.len()..].parse::<u64>() {
+            backups.push(Backup {
+                id: id,
+                path: entry.path(),
+            });
+        }
+    }
+
+    backups.sort_by_key(|b| b.id);
+    Ok(backups)
+}
+
+// Snapshot the hosts file before anything touches it.  A run that finds the
+// file unchanged since the newest backup doesn't add another one, so a few
+// launches in a row can't push the last good copy out of the rotation.
+// Pruning leaves `keep` alone, the backup about to be restored.
+fn backup_hosts(config: &Config, keep: Option<u64>) -> Result<(), io::Error> {
+    let current = fs::read(&config.hosts_path)?;
+    let backups = list_backups(config)?;
+
+    let unchanged = match backups.last() {
+        Some(newest) => fs::read(&newest.path)? == current,
+        None => false,
+    };
+
+    if !unchanged {
+        let mut id = now();
+        if let Some(newest) = backups.last() {
+            if newest.id >= id {
+                id = newest.id + 1;
+            }
+        }
+        write_atomically(&config.backup_path(id), &current, 0o600)?;
+    }
+
+    prune_backups(config, keep)
+}
+
+fn prune_backups(config: &Config, keep: Option<u64>) -> Result<(), io::Error> {
+    let backups = list_backups(config)?;
+    let excess = backups.len().saturating_sub(config.keep_backups);
+    for backup in backups.iter().filter(|b| Some(b.id) != keep).take(excess) {
+        fs::remove_file(&backup.path)?;
+    }
+    Ok(())
+}
+
+fn backups_command(config: &Config, args: &[String], assume_yes: bool) -> i32 {
+    let backups = match list_backups(config) {
+        Ok(backups) => backups,
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't list backups in {}: {}",
+                     config.backup_dir.display(),
+                     e)
+                .unwrap();
+            return 1;
+        }
+    };
+
+    match args.first().map(|s| s.as_str()) {
+        Some("list") | None => {
+            if backups.is_empty() {
+                println!("No backups in {}", config.backup_dir.display());
+            }
+            for backup in backups.iter().rev() {
+                let size = fs::metadata(&backup.path).map(|m| m.len()).unwrap_or(0);
+                println!("{}\t{}\t{} bytes", backup.id, format_time(backup.id), size);
+            }
+            0
+        }
+        Some("restore") => {
+            let id = match args.get(1).and_then(|id| id.parse::<u64>().ok()) {
+                Some(id) => id,
+                None => {
+                    writeln!(&mut std::io::stderr(),
+                             "Usage: backups restore <id>, see `backups list` for ids")
+                        .unwrap();
+                    return 2;
+                }
+            };
+            match backups.iter().find(|b| b.id == id) {
+                Some(backup) => restore_backup(config, backup, assume_yes),
+                None => {
+                    writeln!(&mut std::io::stderr(), "No backup with id {}", id).unwrap();
+                    1
+                }
+            }
+        }
+        Some(other) => {
+            writeln!(&mut std::io::stderr(), "Unknown backups command: {}", other).unwrap();
+            2
+        }
+    }
+}
+
+fn restore_backup(config: &Config, backup: &Backup, assume_yes: bool) -> i32 {
+    let read = |path: &Path| {
+        fs::read(path).map(|bytes| String::from_utf8_lossy(&bytes).into_owned())
+    };
+    let (current, restored) = match (read(&config.hosts_path), read(&backup.path)) {
+        (Ok(current), Ok(restored)) => (current, restored),
+        (Err(e), _) | (_, Err(e)) => {
+            writeln!(&mut std::io::stderr(), "Couldn't read backup: {}", e).unwrap();
+            return 1;
+        }
+    };
+
+    if current == restored {
+        println!("{} already matches backup {}", config.hosts_path.display(), backup.id);
+        return 0;
+    }
+
+    println!("--- {}", config.hosts_path.display());
+    println!("+++ {}", backup.path.display());
+    print_diff(&diff_lines(&current.lines().collect::<Vec<_>>(),
+                           &restored.lines().collect::<Vec<_>>()));
+
+    if !assume_yes {
+        if unsafe { libc::isatty(0) } == 0 {
+            writeln!(&mut std::io::stderr(),
+                     "Not restoring without a terminal to confirm on, pass --yes to skip the \
+                      question")
+                .unwrap();
+            return 1;
+        }
+        print!("Restore backup {}? [y/N] ", backup.id);
+        io::stdout().flush().unwrap();
+        let mut answer = String::new();
+        if io::stdin().read_line(&mut answer).is_err() ||
+           !answer.trim().eq_ignore_ascii_case("y") {
+            println!("Nothing restored.");
+            return 1;
+        }
+    }
+
+    if let Err(e) = backup_hosts(config, Some(backup.id)) {
+        writeln!(&mut std::io::stderr(),
+                 "Couldn't back up {}: {}.  Try running with sudo.",
+                 config.hosts_path.display(),
+                 e)
+            .unwrap();
+        return 1;
+    }
+
+    // The daemon would otherwise undo the restore straight away.
+    let recorded = record_section(config, &render_section(&parse_hosts(&restored)));
+    match recorded.and_then(|_| write_atomically(&config.hosts_path, restored.as_bytes(), HOSTS_MODE)) {
+        Ok(_) => {
+            println!("Restored backup {}", backup.id);
+            0
+        }
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't restore {}: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            1
+        }
+    }
+}
+
+enum DiffLine<'a> {
+    Same(&'a str),
+    Removed(&'a str),
+    Added(&'a str),
+}
+
+// Plain LCS diff over whatever is left after trimming the common prefix and
+// suffix.  Hosts files are small, but guard against a pathological pair of
+// huge blocklists by falling back to "all removed, all added".
+fn diff_lines<'a>(old: &[&'a str], new: &[&'a str]) -> Vec<DiffLine<'a>> {
+    let prefix = old.iter().zip(new).take_while(|&(a, b)| a == b).count();
+    let suffix = old[prefix..]
+                     .iter()
+                     .rev()
+                     .zip(new[prefix..].iter().rev())
+                     .take_while(|&(a, b)| a == b)
+                     .count();
+    let old_mid = &old[prefix..old.len() - suffix];
+    let new_mid = &new[prefix..new.len() - suffix];
+
+    let mut out: Vec<DiffLine> = old[..prefix].iter().map(|l| DiffLine::Same(l)).collect();
+
+    if old_mid.len() * new_mid.len() > 4_000_000 {
+        out.extend(old_mid.iter().map(|l| DiffLine::Removed(l)));
+        out.extend(new_mid.iter().map(|l| DiffLine::Added(l)));
+    } else {
+        let (n, m) = (old_mid.len(), new_mid.len());
+        // lcs[i][j] is the LCS length of old_mid[i..] and new_mid[j..]
+        let mut lcs = vec![vec![0usize; m + 1]; n + 1];
+        for i in (0..n).rev() {
+            for j in (0..m).rev() {
+                lcs[i][j] = if old_mid[i] == new_mid[j] {
+                    lcs[i + 1][j + 1] + 1
+                } else {
+                    std::cmp::max(lcs[i + 1][j], lcs[i][j + 1])
+                };
+            }
+        }
+
+        let (mut i, mut j) = (0, 0);
+        while i < n || j < m {
+            if i < n && j < m && old_mid[i] == new_mid[j] {
+                out.push(DiffLine::Same(old_mid[i]));
+                i += 1;
+                j += 1;
+            } else if j < m && (i == n || lcs[i][j + 1] >= lcs[i + 1][j]) {
+                out.push(DiffLine::Added(new_mid[j]));
+                j += 1;
+            } else {
+                out.push(DiffLine::Removed(old_mid[i]));
+                i += 1;
+            }
+        }
+    }
+
+    out.extend(old[old.len() - suffix..].iter().map(|l| DiffLine::Same(l)));
+    out
+}
+
+// Only show unchanged lines near a change.
+fn print_diff(diff: &[DiffLine]) {
+    let context = 2;
+    let changed: Vec<bool> = diff.iter()
+                                 .map(|l| match *l {
+                                     DiffLine::Same(_) => false,
+                                     _ => true,
+                                 })
+                                 .collect();
+    let mut skipped = false;
+
+    for (i, line) in diff.iter().enumerate() {
+        let lo = i.saturating_sub(context);
+        let hi = min(i + context + 1, diff.len());
+        if !changed[lo..hi].iter().any(|&c| c) {
+            skipped = true;
+            continue;
+        }
+        if skipped {
+            println!("@@");
+            skipped = false;
+        }
+        match *line {
+            DiffLine::Same(l) => println!(" {}", l),
+            DiffLine::Removed(l) => println!("-{}", l),
+            DiffLine::Added(l) => println!("+{}", l),
+        }
+    }
+}
+
+fn now() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+fn format_time(secs: u64) -> String {
+    let t = secs as libc::time_t;
+    let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+    unsafe { libc::localtime_r(&t, &mut tm) };
+    format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec)
+}
+
+//////////////
+// Commands //
+//////////////
+// Runs one of the single domain commands through the same state functions as
+// the TUI, so removing or unblocking still costs a passphrase.
+//
+// `group` says which group `add` and `group` put domains in, and makes
+// `block`/`unblock` without domains act on the whole group.  `until` makes an
+// unblock temporary.
+fn domains_command(config: &Config,
+                   state: State,
+                   command: &str,
+                   args: &[String],
+                   group: Option<&str>,
+                   until: Option<u64>)
+                   -> i32 {
+    if (command == "remove" || command == "unblock") && !cfg!(feature = "commandline_unblock") {
+        writeln!(&mut std::io::stderr(),
+                 "{} via commandline disabled in this build",
+                 command)
+            .unwrap();
+        return 1;
+    }
+    if let Some(group) = group {
+        if !group.is_empty() && !valid_group(group) {
+            writeln!(&mut std::io::stderr(), "not a group name: {}", group).unwrap();
+            return 2;
+        }
+    }
+
+    let mut state = state;
+    let mut failed = false;
+    // Only reported once it's actually saved.
+    let mut done = Vec::new();
+
+    if args.is_empty() {
+        match (command, group) {
+            ("block", Some(group)) | ("unblock", Some(group)) => {
+                if !state.domains.iter().any(|d| in_group(d, Some(group))) {
+                    writeln!(&mut std::io::stderr(), "no domains in group {}", group).unwrap();
+                    return 1;
+                }
+                state = if command == "block" {
+                    block_all(state, Some(group))
+                } else {
+                    unblock_all(state, Some(group))
+                };
+                for domain in &mut state.domains {
+                    if in_group(domain, Some(group)) && until.is_some() {
+                        domain.until = until;
+                    }
+                }
+                done.push(format!("{} group {}", past_tense(command), group));
+            }
+            _ => {
+                writeln!(&mut std::io::stderr(), "Usage: {} <domain>...", command).unwrap();
+                return 2;
+            }
+        }
+    }
+
+    for url in args {
+        let found = state.domains.iter().position(|d| &d.url == url);
+        match (command, found) {
+            ("add", None) => {
+                if valid_domain(url) {
+                    state = add_url(&state, url);
+                    if let Some(group) = group {
+                        state.selected = state.domains.len() - 1;
+                        state = set_group(&state, group);
+                    }
+                    done.push(format!("added {}", url));
+                } else {
+                    writeln!(&mut std::io::stderr(), "not a domain: {}", url).unwrap();
+                    failed = true;
+                }
+            }
+            ("add", Some(_)) => println!("{} is already on the list", url),
+            (_, None) => {
+                writeln!(&mut std::io::stderr(), "{} is not on the list", url).unwrap();
+                failed = true;
+            }
+            (_, Some(i)) => {
+                let mut selected = state.clone();
+                selected.selected = i;
+                state = match (command, &state.domains[i].status) {
+                    ("group", _) => set_group(&selected, group.unwrap_or("")),
+                    ("remove", _) => delete_selected(&selected),
+                    ("block", &DomainStatus::Unblocked) |
+                    ("unblock", &DomainStatus::Blocked) => toggle_block(&selected),
+                    _ => state,
+                };
+                if command == "unblock" && until.is_some() {
+                    state.domains[i].until = until;
+                }
+                done.push(format!("{} {}", past_tense(command), url));
+            }
+        }
+    }
+
+    finish_command(config, &state, command, &done, failed)
+}
+
+// Asks for the passphrase if the command unblocked anything, then saves and
+// reports what was done.
+fn finish_command(config: &Config,
+                  state: &State,
+                  command: &str,
+                  done: &[String],
+                  failed: bool)
+                  -> i32 {
+    let mut reason = String::new();
+    if let Status::Dirty = state.status {
+        if unsafe { libc::isatty(0) } == 0 {
+            writeln!(&mut std::io::stderr(),
+                     "Refusing to {} without a terminal to type the passphrase on, nothing \
+                      was saved.",
+                     command)
+                .unwrap();
+            return 1;
+        }
+        reason = match ask_passphrase(state) {
+            Some(reason) => reason,
+            None => {
+                println!("Nothing saved.");
+                return 1;
+            }
+        };
+        for left in (1..=state.cooldown).rev() {
+            print!("\rSaving in {} seconds, ctrl-c to cancel... ", left);
+            io::stdout().flush().unwrap();
+            thread::sleep(Duration::from_secs(1));
+        }
+        if state.cooldown > 0 {
+            println!();
+        }
+    }
+
+    match save_hosts(config, state) {
+        Ok(_) => {
+            if let Status::Dirty = state.status {
+                if let Err(e) = record_unblocks(config, 1) {
+                    writeln!(&mut std::io::stderr(),
+                             "warning: couldn't record the unblock: {}",
+                             e)
+                        .unwrap();
+                }
+            }
+            if let Err(e) = record_journal(config, &unblocked_entries(state, &reason, now())) {
+                writeln!(&mut std::io::stderr(), "warning: couldn't write the journal: {}", e)
+                    .unwrap();
+            }
+            for line in done {
+                println!("{}", line);
+            }
+            if failed { 1 } else { 0 }
+        }
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't save {}, it has been left untouched: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            1
+        }
+    }
+}
+
+// `schedule <spec>|- <domain>...`, or with a group `schedule <spec>|-` for
+// the whole group.  "-" drops the schedule.
+fn schedule_command(config: &Config, state: State, args: &[String], group: Option<&str>) -> i32 {
+    let (spec, domains) = match args.split_first() {
+        Some((spec, domains)) if !domains.is_empty() || group.is_some() => (spec, domains),
+        _ => {
+            writeln!(&mut std::io::stderr(),
+                     "Usage: schedule <spec>|- <domain>...\n       \
+                      schedule --group <group> <spec>|-")
+                .unwrap();
+            return 2;
+        }
+    };
+    let schedule = if spec == "-" { None } else { Some(spec.as_str()) };
+    if let Some(spec) = schedule {
+        if let Err(e) = parse_schedule(spec) {
+            writeln!(&mut std::io::stderr(), "invalid schedule: {}", e).unwrap();
+            return 2;
+        }
+    }
+    let described = schedule.map_or(String::from("unscheduled"),
+                                     |s| format!("scheduled {}", s));
+
+    let mut state = state;
+    let mut failed = false;
+    let mut done = Vec::new();
+
+    if let Some(group) = group {
+        if !valid_group(group) {
+            writeln!(&mut std::io::stderr(), "not a group name: {}", group).unwrap();
+            return 2;
+        }
+        state = set_group_schedule(&state, group, schedule);
+        done.push(format!("{} group {}", described, group));
+    }
+    for url in domains {
+        match state.domains.iter().position(|d| &d.url == url) {
+            Some(i) => {
+                let mut selected = state.clone();
+                selected.selected = i;
+                state = set_schedule(&selected, schedule);
+                done.push(format!("{} {}", described, url));
+            }
+            None => {
+                writeln!(&mut std::io::stderr(), "{} is not on the list", url).unwrap();
+                failed = true;
+            }
+        }
+    }
+
+    finish_command(config, &state, "change schedules", &done, failed)
+}
+
+// `challenge --group <group> <kind>|-`.  "-" goes back to the configured
+// challenge.  Either way it's behind the group's current challenge.
+fn challenge_command(config: &Config, state: State, args: &[String], group: Option<&str>) -> i32 {
+    let (group, kind) = match (group, args) {
+        (Some(group), [kind]) => (group, kind),
+        _ => {
+            writeln!(&mut std::io::stderr(),
+                     "Usage: challenge --group <group> <{}>|-",
+                     CHALLENGE_KINDS.join("|"))
+                .unwrap();
+            return 2;
+        }
+    };
+    if !valid_group(group) {
+        writeln!(&mut std::io::stderr(), "not a group name: {}", group).unwrap();
+        return 2;
+    }
+    let challenge = if kind == "-" { None } else { Some(kind.as_str()) };
+    if let Some(kind) = challenge {
+        if !CHALLENGE_KINDS.contains(&kind) {
+            writeln!(&mut std::io::stderr(),
+                     "unknown challenge {}, expected one of {}",
+                     kind,
+                     CHALLENGE_KINDS.join(", "))
+                .unwrap();
+            return 2;
+        }
+    }
+
+    let state = set_group_challenge(&state, group, challenge);
+    let done = [format!("group {} challenge {}", group, challenge.unwrap_or("default"))];
+    finish_command(config, &state, "change challenges", &done, false)
+}
+
+// Brings every scheduled domain in line with the current time, for cron or a
+// systemd timer.
+fn apply_command(config: &Config, state: &State) -> i32 {
+    let (changes, new_state) = apply_schedules(state, now());
+    match save_hosts(config, &new_state) {
+        Ok(_) => {
+            for line in &changes {
+                println!("{}", line);
+            }
+            0
+        }
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't save {}, it has been left untouched: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            1
+        }
+    }
+}
+
+// For cron, in case nothing else runs hostblock for a while.
+fn expire_command(config: &Config, state: &State) -> i32 {
+    let (expired, new_state) = expire_unblocks(state, now());
+    match save_hosts(config, &new_state) {
+        Ok(_) => {
+            for line in &expired {
+                println!("{}", line);
+            }
+            0
+        }
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't save {}, it has been left untouched: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            1
+        }
+    }
+}
+
+enum ImportLine {
+    Domains(Vec<String>),
+    Ignored,
+    Rejected,
+}
+
+// Reads plain domain lists, hosts files ("0.0.0.0 domain ...") and adblock
+// "||domain^" rules.  Only adds domains, so it doesn't need the passphrase.
+fn import_command(config: &Config, state: State, path: &str) -> i32 {
+    let mut text = String::new();
+    let read = if path == "-" {
+        io::stdin().read_to_string(&mut text)
+    } else {
+        File::open(path).and_then(|mut f| f.read_to_string(&mut text))
+    };
+    if let Err(e) = read {
+        writeln!(&mut std::io::stderr(), "Couldn't read {}: {}", path, e).unwrap();
+        return 1;
+    }
+
+    // Imported names come lowercased, the list may not be.
+    let mut seen: HashSet<String> = state.domains.iter().map(|d| d.url.to_lowercase()).collect();
+    let mut new_state = state.clone();
+    let (mut added, mut skipped, mut rejected) = (0, 0, 0);
+
+    for (i, line) in text.lines().enumerate() {
+        match parse_import_line(line) {
+            ImportLine::Domains(domains) => {
+                for domain in domains {
+                    if !valid_domain(&domain) {
+                        writeln!(&mut std::io::stderr(),
+                                 "line {}: rejected \"{}\"",
+                                 i + 1,
+                                 domain)
+                            .unwrap();
+                        rejected += 1;
+                    } else if seen.contains(&domain) {
+                        skipped += 1;
+                    } else {
+                        new_state.domains.push(new_domain(&domain));
+                        seen.insert(domain);
+                        added += 1;
+                    }
+                }
+            }
+            ImportLine::Ignored => {}
+            ImportLine::Rejected => {
+                writeln!(&mut std::io::stderr(),
+                         "line {}: rejected \"{}\"",
+                         i + 1,
+                         line.trim())
+                    .unwrap();
+                rejected += 1;
+            }
+        }
+    }
+
+    if added > 0 {
+        if let Err(e) = save_hosts(config, &new_state) {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't save {}, it has been left untouched: {}",
+                     config.hosts_path.display(),
+                     e)
+                .unwrap();
+            return 1;
+        }
+    }
+    println!("added {}, skipped {} already on the list or repeated, rejected {}",
+             added,
+             skipped,
+             rejected);
+    0
+}
+
+fn parse_import_line(line: &str) -> ImportLine {
+    let line = line.trim();
+    // '#' comments in hosts files and plain lists, '!' and "[Adblock ...]"
+    // headers in filter lists.
+    if line.is_empty() || line.starts_with('#') || line.starts_with('!') ||
+       line.starts_with('[') {
+        return ImportLine::Ignored;
+    }
+    // Exceptions and element hiding rules have nothing to do with DNS.
+    if line.starts_with("@@") || line.contains("##") || line.contains("#@#") {
+        return ImportLine::Ignored;
+    }
+
+    if line.starts_with("||") {
+        // ||example.com^ or ||example.com^$third-party, anything with a path
+        // or wildcard can't be expressed in a hosts file.
+        let rule = &line[2..];
+        let end = rule.find(|c| c == '^' || c == '$').unwrap_or(rule.len());
+        let domain = &rule[..end];
+        if domain.contains('/') || domain.contains('*') {
+            return ImportLine::Rejected;
+        }
+        return ImportLine::Domains(vec![domain.to_lowercase()]);
+    }
+
+    let body = line.split('#').next().unwrap_or("");
+    let fields: Vec<&str> = body.split_whitespace().collect();
+    match fields.first().map(|f| f.parse::<IpAddr>().is_ok()) {
+        Some(true) => {
+            ImportLine::Domains(fields[1..]
+                                    .iter()
+                                    .map(|f| f.to_lowercase())
+                                    .filter(|f| !is_local_name(f))
+                                    .collect())
+        }
+        Some(false) if fields.len() == 1 => ImportLine::Domains(vec![fields[0].to_lowercase()]),
+        _ => ImportLine::Rejected,
+    }
 }
 
-fn backspace(state: &State) -> State {
-    let mut new_state = state.clone();
-    new_state.adding.pop();
-    new_state
+// The boilerplate at the top of most published hosts files.
+fn is_local_name(name: &str) -> bool {
+    match name {
+        "localhost" | "localhost.localdomain" | "local" | "broadcasthost" | "0.0.0.0" => true,
+        _ => name.starts_with("ip6-"),
+    }
 }
 
-fn password_backspace(state: &State) -> State {
-    let mut new_state = state.clone();
-    new_state.pass_input.pop();
-    new_state
+// Renders what is blocked right now for other blockers, e.g. a router's
+// dnsmasq or a resolver.  Unblocked domains are left out, and every name of an
+// entry (aliases included) points at the entry's sinks.
+fn export_command(state: &State, format: ExportFormat) -> i32 {
+    let blocked = state.domains.iter().filter(|d| d.status == DomainStatus::Blocked);
+
+    match format {
+        ExportFormat::Hosts => println!("# exported by hostblock"),
+        ExportFormat::Dnsmasq => println!("# exported by hostblock"),
+        ExportFormat::Unbound => println!("# exported by hostblock\nserver:"),
+        ExportFormat::Adblock => println!("[Adblock Plus 2.0]\n! Title: exported by hostblock"),
+        ExportFormat::Plain => {}
+    }
+
+    for domain in blocked {
+        let sinks: Vec<&String> = domain.ip.iter().chain(domain.ip6.iter()).collect();
+        for name in Some(&domain.url).into_iter().chain(domain.aliases.iter()) {
+            match format {
+                ExportFormat::Plain => println!("{}", name),
+                ExportFormat::Hosts => {
+                    for sink in &sinks {
+                        println!("{}\t{}", sink, name);
+                    }
+                }
+                ExportFormat::Dnsmasq => {
+                    for sink in &sinks {
+                        println!("address=/{}/{}", name, sink);
+                    }
+                }
+                ExportFormat::Unbound => {
+                    println!("    local-zone: \"{}.\" redirect", name);
+                    for sink in &sinks {
+                        let record = match sink.parse::<IpAddr>() {
+                            Ok(IpAddr::V6(_)) => "AAAA",
+                            _ => "A",
+                        };
+                        println!("    local-data: \"{}. {} {}\"", name, record, sink);
+                    }
+                }
+                ExportFormat::Adblock => println!("||{}^", name),
+            }
+        }
+    }
+    0
 }
 
-fn block_all(state:State) -> State{
-    let mut new_state = state.clone();
-    new_state.domains = new_state.domains.into_iter().map(|domain| Domain{
-        url:domain.url.clone(),
-        status:DomainStatus::Blocked
-    }).collect();
-    new_state
+fn groups_command(state: &State) -> i32 {
+    for (name, blocked, total) in groups(&state.domains) {
+        let challenge = state.group_settings
+                             .iter()
+                             .find(|g| g.name == name)
+                             .and_then(|g| g.challenge.as_ref())
+                             .map_or(String::new(), |c| format!(", {} challenge", c));
+        println!("{}\t{} of {} blocked{}", name, blocked, total, challenge);
+    }
+    0
 }
 
-fn unblock_all(state:State) -> State{
-    let mut new_state = state.clone();
-    new_state.domains = new_state.domains.into_iter().map(|domain| Domain{
-        url:domain.url.clone(),
-        status:DomainStatus::Unblocked
-    }).collect();
-    new_state.status = Status::Dirty;
-    new_state.correct_pass = gen_pass(6);
-    new_state.mode = Mode::Password;
-    new_state
+// Every unblock with its reason, then what stands out.
+fn journal_command(config: &Config) -> i32 {
+    let entries = match load_journal(config) {
+        Ok(entries) => entries,
+        Err(e) => {
+            writeln!(&mut std::io::stderr(),
+                     "Couldn't read {}: {}",
+                     journal_path(config).display(),
+                     e)
+                .unwrap();
+            return 1;
+        }
+    };
+    if entries.is_empty() {
+        println!("Nothing unblocked yet.");
+        return 0;
+    }
+
+    let mut per_domain: HashMap<&str, usize> = HashMap::new();
+    // Days in order, format_time starts with the date.
+    let mut per_day: Vec<(String, usize)> = Vec::new();
+    for entry in &entries {
+        println!("{}  {}  {}  {}",
+                 format_time(entry.at),
+                 entry.domain,
+                 entry.minutes.map_or(String::from("-"), |m| format!("{}m", m)),
+                 entry.reason);
+        *per_domain.entry(&entry.domain).or_insert(0) += 1;
+        let day = format_time(entry.at)[..10].to_string();
+        match per_day.last_mut() {
+            Some(&mut (ref last, ref mut count)) if *last == day => *count += 1,
+            _ => per_day.push((day, 1)),
+        }
+    }
+
+    let (domain, count) = per_domain.iter()
+                                    .max_by(|a, b| a.1.cmp(b.1).then(b.0.cmp(a.0)))
+                                    .unwrap();
+    println!();
+    println!("Most unblocked: {} ({} time{})", domain, count, if *count == 1 { "" } else { "s" });
+    println!("Unblocks per day:");
+    for (day, count) in &per_day {
+        println!("  {}  {}", day, count);
+    }
+    0
 }
 
-fn toggle_block(state: &State) -> State {
-    let mut new_state = state.clone();
-    let mut dirty = false;
+fn list_command(state: &State, format: Format) -> i32 {
+    match format {
+        Format::Plain => {
+            for domain in &state.domains {
+                println!("{}", render_domain(domain));
+            }
+        }
+        Format::Tsv => {
+            println!("status\tdomain\tgroup\tsink\tsink6\taliases\tcomment\tschedule\tuntil");
+            for domain in &state.domains {
+                println!("{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}\t{}",
+                         status_name(&domain.status),
+                         domain.url,
+                         domain.group.as_ref().map_or("", |s| s),
+                         domain.ip.as_ref().map_or("", |s| s),
+                         domain.ip6.as_ref().map_or("", |s| s),
+                         domain.aliases.join(","),
+                         domain.comment.as_ref().map_or("", |s| s),
+                         schedule_for(domain, &state.group_settings).unwrap_or(""),
+                         domain.until.map_or(String::new(), format_time));
+            }
+        }
+        Format::Json => {
+            let entries: Vec<String> = state.domains
+                                            .iter()
+                                            .map(|d| domain_json(d, &state.group_settings))
+                                            .collect();
+            println!("[{}]", entries.join(","));
+        }
+    }
+    0
+}
 
-    let mut d = state.domains.clone();
-    d[state.selected] = Domain {
-        url: d[state.selected].url.clone(),
-        status: match d[state.selected].status {
-            DomainStatus::Blocked => {
-                dirty = true;
-                DomainStatus::Unblocked
-            }
-            DomainStatus::Unblocked => DomainStatus::Blocked,
-        },
-    };
+fn status_command(state: &State, format: Format) -> i32 {
+    let total = state.domains.len();
+    let blocked = state.domains
+                       .iter()
+                       .filter(|d| d.status == DomainStatus::Blocked)
+                       .count();
 
-    new_state.domains = d;
-    if dirty {
-        new_state.status = Status::Dirty;
+    match format {
+        Format::Plain => println!("{} of {} domains blocked", blocked, total),
+        Format::Tsv => println!("{}\t{}\t{}", blocked, total - blocked, total),
+        Format::Json => println!("{{{}}}", status_fields(state)),
     }
 
-    new_state
+    if blocked == 0 {
+        STATUS_NONE_BLOCKED
+    } else if blocked < total {
+        STATUS_SOME_UNBLOCKED
+    } else {
+        STATUS_ALL_BLOCKED
+    }
 }
 
-/////////////////
-// Persistence //
-/////////////////
-fn read_hosts() -> String {
-    let mut hosts_file = match File::open("/etc/hosts") {
-        Ok(file) => file,
-        Err(_) => panic!("Couldn't access hosts file, try running with sudo."),
-    };
+// The counts of `status --format json`, without the braces so the server
+// can add its own fields.
+fn status_fields(state: &State) -> String {
+    let total = state.domains.len();
+    let blocked = state.domains
+                       .iter()
+                       .filter(|d| d.status == DomainStatus::Blocked)
+                       .count();
+    format!("\"blocked\":{},\"unblocked\":{},\"total\":{}",
+            blocked,
+            total - blocked,
+            total)
+}
 
-    // TODO(cgag): just return file handle so it's not all read into memory?
-    // We just iterate over the lines atm.
-    let mut s = String::new();
-    match hosts_file.read_to_string(&mut s) {
-        Ok(_) => {}
-        Err(e) => panic!("Couldn't read hosts file: {}", e),
+fn status_name(status: &DomainStatus) -> &'static str {
+    match *status {
+        DomainStatus::Blocked => "blocked",
+        DomainStatus::Unblocked => "unblocked",
     }
-    s
 }
 
-fn parse_hosts(hosts_text: String) -> Vec<Domain> {
-    hosts_text.lines()
-        .take_while(|s| !s.starts_with("### End HostBlock"))
-        .skip_while(|s| !s.starts_with("### HostBlock"))
-        .skip(1)
-        .map(|line| {
-            let ip = match line.split_whitespace().nth(0) {
-                Some(ip) => ip,
-                None => panic!("Failed to parse a valid IP from line: {}", line),
-            };
-            let url = match line.split_whitespace().nth(1) {
-                Some(url) => String::from(url),
-                None => panic!("Failed to parse a valid URL from line: {}", line),
-            };
+// `schedule` is the one in effect, which may come from the domain's group.
+fn domain_json(domain: &Domain, groups: &[GroupSettings]) -> String {
+    let opt = |s: &Option<String>| s.as_ref().map_or(String::from("null"), |s| json_str(s));
+    let aliases: Vec<String> = domain.aliases.iter().map(|a| json_str(a)).collect();
+    format!("{{\"domain\":{},\"status\":{},\"group\":{},\"sink\":{},\"sink6\":{},\
+             \"aliases\":[{}],\"comment\":{},\"schedule\":{},\"until\":{}}}",
+            json_str(&domain.url),
+            json_str(status_name(&domain.status)),
+            opt(&domain.group),
+            opt(&domain.ip),
+            opt(&domain.ip6),
+            aliases.join(","),
+            opt(&domain.comment),
+            opt(&schedule_for(domain, groups).map(String::from)),
+            domain.until.map_or(String::from("null"), |t| t.to_string()))
+}
 
-            Domain {
-                url: url,
-                status: match UnicodeSegmentation::graphemes(ip, true)
-                                  .nth(0)
-                                  .unwrap() {
-                    "#" => DomainStatus::Unblocked,
-                    _ => DomainStatus::Blocked,
-                },
-            }
-        })
-        .collect::<Vec<Domain>>()
+fn json_str(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\r' => out.push_str("\\r"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
 }
 
-fn save_hosts(state: &State) -> Result<(), io::Error> {
-    let mut hosts_file = File::open("/etc/hosts")?;
-    let mut hosts_text = String::new();
-    hosts_file.read_to_string(&mut hosts_text)?;
+fn past_tense(command: &str) -> &'static str {
+    match command {
+        "group" => "regrouped",
+        "remove" => "removed",
+        "block" => "blocked",
+        _ => "unblocked",
+    }
+}
 
-    let before_block = hosts_text.lines()
-                                 .take_while(|s| !s.starts_with("### HostBlock"));
+// The command line version of the gate: waits, asks for the reasons, then
+// asks again until the passphrase is typed correctly.  The reason, or None
+// on EOF.
+fn ask_passphrase(state: &State) -> Option<String> {
+    fn read_answer() -> Option<String> {
+        io::stdout().flush().unwrap();
+        let mut input = String::new();
+        match io::stdin().read_line(&mut input) {
+            Ok(0) | Err(_) => None,
+            // The end of a paste is left over for the next line.
+            Ok(_) => {
+                Some(input.trim_end_matches(|c| c == '\n' || c == '\r').replace("\x1b[201~", ""))
+            }
+        }
+    }
 
-    let after_block = hosts_text.lines()
-                                .skip_while(|s| !s.starts_with("### End HostBlock"))
-                                .skip(1); // drop the ### End hostblock line
+    for left in (1..state.friction.wait + 1).rev() {
+        print!("\rUnblocking a lot lately, wait {} seconds... ", left);
+        io::stdout().flush().unwrap();
+        thread::sleep(Duration::from_secs(1));
+    }
+    if state.friction.wait > 0 {
+        println!();
+    }
 
-    let mut new_hosts = String::new();
-    for line in before_block.chain(after_block) {
-        new_hosts.push_str(line);
-        new_hosts.push_str("\n");
-    };
+    let mut reason = String::new();
+    let mut left = state.friction.reasons;
+    while left > 0 {
+        if reason.is_empty() {
+            print!("Why? ");
+        } else {
+            print!("Type the same reason again ({} more): ", left);
+        }
+        let answer = match read_answer() {
+            Some(answer) => answer,
+            None => return None,
+        };
+        if answer.trim().is_empty() || (!reason.is_empty() && answer != reason) {
+            continue;
+        }
+        reason = answer;
+        left -= 1;
+    }
 
-    new_hosts.push_str("### HostBlock\n");
-    for domain in &state.domains {
-        let block_marker = match domain.status {
-            DomainStatus::Blocked => "",
-            DomainStatus::Unblocked => "#",
+    let _paste = BracketedPaste::enable(state.paste_check);
+    let mut challenge = new_challenge(state, &Mode::Password);
+    println!("{}:", challenge.title);
+    for line in wrap(&challenge.prompt, 76) {
+        println!("  {}", line);
+    }
+    loop {
+        print!("> ");
+        let answer = if state.paste_check {
+            read_typed()
+        } else {
+            read_answer().map(|answer| (answer, false))
         };
-        new_hosts.push_str(block_marker);
-        new_hosts.push_str("127.0.0.1\t");
-        new_hosts.push_str(&domain.url);
-        new_hosts.push_str("\n");
-    };
-    new_hosts.push_str("### End HostBlock\n");
+        match answer {
+            Some((_, true)) => {
+                challenge = new_challenge(state, &Mode::Password);
+                println!("No pasting, type this one instead:");
+                for line in wrap(&challenge.prompt, 76) {
+                    println!("  {}", line);
+                }
+            }
+            Some((ref input, _)) if *input == challenge.answer => return Some(reason),
+            Some(_) => println!("That's not it, try again (ctrl-d to give up)."),
+            None => return None,
+        }
+    }
+}
 
-    let mut file = File::create("/etc/hosts")?;
-    file.write_all(new_hosts.as_bytes())?;
-    Ok(())
+fn valid_group(s: &str) -> bool {
+    !s.is_empty() && s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
 }
 
+fn valid_domain(s: &str) -> bool {
+    !s.is_empty() && s.len() <= 253 && !s.starts_with('.') && !s.starts_with('-') &&
+    s.parse::<IpAddr>().is_err() &&
+    s.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '.' || c == '_')
+}
 
 ///////////////
 // Rendering //
@@ -522,22 +5144,138 @@ fn render_domain(domain: &Domain) -> String {
         DomainStatus::Unblocked => "[ ] ",
     };
 
-    String::from(status_prefix) + &domain.url
+    let group = match domain.group {
+        Some(ref group) => format!(" ({})", group),
+        None => String::new(),
+    };
+
+    let until = match domain.until {
+        Some(until) => {
+            let (_, minute) = local_time(until);
+            format!(" until {:02}:{:02}", minute / 60, minute % 60)
+        }
+        None => String::new(),
+    };
+
+    String::from(status_prefix) + &domain.url + &group + &until
+}
+
+// The list's columns.  Status and domain are always there, the others only
+// when some domain has something to show in them and there's room, giving it
+// up in the order schedule, sink, group.
+#[derive(Clone, Copy, PartialEq)]
+enum Column {
+    // Only while domains are marked or picked in visual mode.
+    Mark,
+    Status,
+    Domain,
+    Group,
+    Schedule,
+    Sink,
+}
+
+fn cell(state: &State, domain: &Domain, column: Column) -> String {
+    match column {
+        Column::Mark => String::new(),
+        Column::Status => {
+            String::from(match domain.status {
+                DomainStatus::Blocked => "[x]",
+                DomainStatus::Unblocked => "[ ]",
+            })
+        }
+        Column::Domain => domain.url.clone(),
+        Column::Group => domain.group.clone().unwrap_or_default(),
+        // A temporary unblock says when it ends instead.
+        Column::Schedule => {
+            match domain.until {
+                Some(until) => {
+                    let (_, minute) = local_time(until);
+                    format!("until {:02}:{:02}", minute / 60, minute % 60)
+                }
+                None => String::from(schedule_for(domain, &state.group_settings).unwrap_or("")),
+            }
+        }
+        Column::Sink => domain.sink.as_ref().unwrap_or(&state.default_sink).clone(),
+    }
+}
+
+// Widths of the columns that fit in `width`, sized to the widest of `rows` so
+// they don't jump around while scrolling.
+fn layout(state: &State, rows: &[usize], width: usize) -> Vec<(Column, usize)> {
+    let widest = |column: Column, cap: usize| {
+        let widest = rows.iter()
+                         .map(|&i| cell(state, &state.domains[i], column).chars().count())
+                         .max()
+                         .unwrap_or(0);
+        min(widest, cap)
+    };
+
+    let mut used = 4 + MIN_DOMAIN_WIDTH;
+    let mut optional = Vec::new();
+    for &(column, cap) in &[(Column::Group, 16), (Column::Sink, 39), (Column::Schedule, 24)] {
+        let w = widest(column, cap);
+        if w > 0 && used + 1 + w <= width {
+            used += 1 + w;
+            optional.push((column, w));
+        }
+    }
+    let room = width.saturating_sub(used - MIN_DOMAIN_WIDTH);
+
+    let mut columns = vec![(Column::Status, 3), (Column::Domain, min(widest(Column::Domain, room), room))];
+    for &column in &[Column::Group, Column::Schedule, Column::Sink] {
+        columns.extend(optional.iter().filter(|c| c.0 == column));
+    }
+    columns
+}
+
+fn render_row(state: &State, domain: &Domain, columns: &[(Column, usize)], picked: bool) -> String {
+    columns.iter()
+           .map(|&(column, width)| {
+               let text = match column {
+                   Column::Mark if picked => String::from("*"),
+                   _ => cell(state, domain, column),
+               };
+               format!("{:1$}", truncate(&text, width), width)
+           })
+           .collect::<Vec<_>>()
+           .join(" ")
+}
+
+fn render_group(name: &str, blocked: usize, total: usize) -> String {
+    let status_prefix = if blocked == total {
+        "[x] "
+    } else if blocked == 0 {
+        "[ ] "
+    } else {
+        "[-] "
+    };
+
+    format!("{}{} ({}/{} blocked)", status_prefix, name, blocked, total)
 }
 
-fn make_label(s: &str) -> String {
+fn make_label(s: &str, width: usize) -> String {
     let prefix_size = 1;
 
+    let s = truncate(s, width.saturating_sub(prefix_size + 3));
     let prefix = str_repeat(String::from(HORIZONTAL_LINE), prefix_size);
     let rest_of_line = str_repeat(String::from(HORIZONTAL_LINE),
-                                  BOX_WIDTH - s.len() - prefix_size - 2);
+                                  width.saturating_sub(s.chars().count() + prefix_size + 2));
+
+    String::from(TOP_LEFT) + &prefix + &s + &rest_of_line + TOP_RIGHT
+}
+
+fn make_bottom_label(s: &str, width: usize) -> String {
+    let s = truncate(s, width.saturating_sub(5));
+    let prefix = str_repeat(String::from(HORIZONTAL_LINE), 1);
+    let rest_of_line = str_repeat(String::from(HORIZONTAL_LINE),
+                                  width.saturating_sub(s.chars().count() + 5));
 
-    String::from(TOP_LEFT) + &prefix + s + &rest_of_line + TOP_RIGHT
+    String::from(BOTTOM_LEFT) + &prefix + " " + &s + " " + &rest_of_line + BOTTOM_RIGHT
 }
 
-fn make_bottom() -> String {
+fn make_bottom(width: usize) -> String {
     let mut line = String::new();
-    for _ in 0..(BOX_WIDTH - 2) {
+    for _ in 0..width.saturating_sub(2) {
         line.push_str(HORIZONTAL_LINE)
     }
     String::from(BOTTOM_LEFT) + &line + BOTTOM_RIGHT
@@ -549,6 +5287,10 @@ trait ScreenWriter {
     fn w(&self, x: usize, y: usize, text: &str);
     fn w_inv(&self, x: usize, y: usize, text: &str);
     fn w_boxed(&self, x: usize, y: usize, text: &str);
+    fn w_boxed_inv(&self, x: usize, y: usize, text: &str);
+    fn w_input(&self, y: usize, text: &str);
+    fn draw_challenge(&self, label: &str, title: Option<&str>, state: &State);
+    fn draw_list(&self, state: &State) -> usize;
     fn draw(&self, state: &State);
 }
 
@@ -563,10 +5305,112 @@ impl ScreenWriter for RustBox {
         self.print(x, y, rustbox::RB_BOLD, Color::Black, Color::White, text);
     }
 
+    // Boxes are as wide as the terminal, anything longer is cut short.
     fn w_boxed(&self, x: usize, y: usize, text: &str) {
         self.w(x, y, VERTICAL_LINE);
-        self.w(x + 2, y, text);
-        self.w(x + BOX_WIDTH - 1, y, VERTICAL_LINE);
+        self.w(x + 2, y, &truncate(text, self.width().saturating_sub(x + 4)));
+        self.w((x + self.width()).saturating_sub(1), y, VERTICAL_LINE);
+    }
+
+    fn w_boxed_inv(&self, x: usize, y: usize, text: &str) {
+        let inner = self.width().saturating_sub(x + 4);
+        self.w(x, y, VERTICAL_LINE);
+        self.w_inv(x + 2, y, &format!("{:1$}", truncate(text, inner), inner));
+        self.w((x + self.width()).saturating_sub(1), y, VERTICAL_LINE);
+    }
+
+    // A line being typed, showing its end when it doesn't fit.
+    fn w_input(&self, y: usize, text: &str) {
+        let inner = self.width().saturating_sub(5);
+        self.w(0, y, VERTICAL_LINE);
+        self.w(2, y, &last_n_chars(text, inner));
+        self.w(min(text.len(), inner) + 2, y, "_");
+        self.w(self.width().saturating_sub(1), y, VERTICAL_LINE);
+    }
+
+    // The Password and Unlock screens: the challenge, then what's been typed.
+    fn draw_challenge(&self, label: &str, title: Option<&str>, state: &State) {
+        self.w(0, 0, &make_label(label, self.width()));
+        if waiting(state) > 0 {
+            self.w_boxed(0, 1, &format!("Unblocking a lot lately, wait {} seconds.",
+                                        waiting(state)));
+            self.w(0, 2, &make_bottom(self.width()));
+            return;
+        }
+
+        let mut y = 1;
+        if state.pasted {
+            self.w_boxed(0, y, "No pasting, type this one instead.");
+            y += 1;
+        }
+        if let Some(title) = title {
+            self.w_boxed(0, y, &format!("{}:", title));
+            y += 1;
+        }
+        for line in wrap(&state.challenge.prompt, self.width().saturating_sub(4)) {
+            self.w_boxed(0, y, &line);
+            y += 1;
+        }
+
+        self.w_input(y, &state.pass_input);
+
+        self.w(0, y + 1, &make_bottom(self.width()));
+    }
+
+    // The domains the filter lets through, returns the first row below them.
+    fn draw_list(&self, state: &State) -> usize {
+        if state.domains.is_empty() {
+            self.w(0, 0, "No domains, hit i to enter insert mode");
+            return 1;
+        }
+
+        let rows = visible(state);
+        let mut label = if state.filter.is_empty() {
+            String::from("Domains")
+        } else {
+            format!("Domains /{} ({} of {})", state.filter, rows.len(), state.domains.len())
+        };
+        let picked = match state.mode {
+            Mode::Visual => targets(state),
+            _ if !state.marked.is_empty() => targets(state),
+            _ => Vec::new(),
+        };
+        match state.mode {
+            Mode::Visual => label.push_str(&format!(", visual: {} picked", picked.len())),
+            _ if !picked.is_empty() => label.push_str(&format!(", {} marked", picked.len())),
+            _ => {}
+        }
+        self.w(0, 0, &make_label(&label, self.width()));
+        if rows.is_empty() {
+            self.w_boxed(0, 1, "Nothing matches, esc clears the filter.");
+            self.w(0, 2, &make_bottom(self.width()));
+            return 3;
+        }
+        let shown: Vec<&usize> = rows.iter().skip(state.scroll).take(list_rows(state)).collect();
+        let mut columns = layout(state, &rows, self.width().saturating_sub(4));
+        if !picked.is_empty() {
+            columns.insert(0, (Column::Mark, 1));
+        }
+        for (row, &&i) in shown.iter().enumerate() {
+            let s = render_row(state, &state.domains[i], &columns, picked.contains(&i));
+            if i == state.selected {
+                self.w_boxed_inv(0, row + 1, &s);
+            } else {
+                self.w_boxed(0, row + 1, &s);
+            }
+        }
+        // Where the view is, when it doesn't show everything.
+        let bottom = if shown.len() < rows.len() {
+            make_bottom_label(&format!("{}-{} of {}",
+                                       state.scroll + 1,
+                                       state.scroll + shown.len(),
+                                       rows.len()),
+                              self.width())
+        } else {
+            make_bottom(self.width())
+        };
+        self.w(0, shown.len() + 1, &bottom);
+        shown.len() + 2
     }
 
     fn draw(&self, state: &State) {
@@ -574,60 +5418,137 @@ impl ScreenWriter for RustBox {
         self.present();
 
         match state.mode {
-            Mode::Normal => {
-                if state.domains.is_empty() {
-                    self.w(0, 0, "No domains, hit i to enter insert mode");
-                } else {
-                    self.w(0, 0, &make_label("Domains"));
-                    for (i, domain) in state.domains.iter().enumerate() {
-                        let y = i + 1;
-                        let s = truncate(&render_domain(domain), 33);
-                        self.w(0, y, VERTICAL_LINE);
-                        if i == state.selected {
-                            self.w_inv(2, y, &s);
-                        } else {
-                            self.w(2, y, &s);
-                        }
-                        self.w(BOX_WIDTH - 1, y, VERTICAL_LINE);
-                    }
-                    self.w(0, state.domains.len() + 1, &make_bottom());
+            Mode::Normal | Mode::Search | Mode::Visual => {
+                let mut y = self.draw_list(state);
+                if let Mode::Search = state.mode {
+                    self.w(0, y, &format!("/{}_", state.filter));
+                    y += 1;
+                }
+                let history = history_line(state);
+                if !history.is_empty() {
+                    self.w(0, y, &history);
                 }
             }
             Mode::Insert => {
-                self.w(0, 0, &make_label("Add domain"));
+                self.w(0, 0, &make_label("Add domain", self.width()));
 
-                // TODO(cgag): method like w_bordered(...)
-                self.w(0, 1, VERTICAL_LINE);
-                self.w(2, 1, &last_n_chars(&state.adding, BOX_WIDTH - 5));
-                self.w(min(state.adding.len() + 2, BOX_WIDTH - 3), 1, "_");
-                self.w(BOX_WIDTH - 1, 1, VERTICAL_LINE);
+                self.w_input(1, &state.adding);
 
                 self.w_boxed(0, 2, "Press enter to finish.");
 
-                self.w(0, 3, &make_bottom());
+                self.w(0, 3, &make_bottom(self.width()));
+            }
+            Mode::Sink => {
+                let url = &state.domains[state.selected].url;
+                self.w(0, 0, &make_label(&format!("Sink for {}", url), self.width()));
+
+                self.w_input(1, &state.adding);
+
+                self.w_boxed(0, 2, &format!("Leave empty to use {}.", state.default_sink));
+                self.w_boxed(0, 3, "Press enter to finish.");
+
+                self.w(0, 4, &make_bottom(self.width()));
+            }
+            Mode::Groups => {
+                let groups = groups(&state.domains);
+                self.w(0, 0, &make_label("Groups", self.width()));
+                if groups.is_empty() {
+                    self.w_boxed(0, 1, "No groups yet, press a on a domain to add one.");
+                }
+                for (i, &(ref name, blocked, total)) in groups.iter().enumerate() {
+                    let s = render_group(name, blocked, total);
+                    if i == state.group_selected {
+                        self.w_boxed_inv(0, i + 1, &s);
+                    } else {
+                        self.w_boxed(0, i + 1, &s);
+                    }
+                }
+                let y = max(groups.len(), 1) + 1;
+                self.w_boxed(0, y, "<space> blocks/unblocks the whole group.");
+                self.w(0, y + 1, &make_bottom(self.width()));
+            }
+            Mode::Group => {
+                let targets = targets(state);
+                let label = match targets.as_slice() {
+                    &[i] => format!("Group for {}", state.domains[i].url),
+                    _ => format!("Group for {} domains", targets.len()),
+                };
+                self.w(0, 0, &make_label(&label, self.width()));
+
+                self.w_input(1, &state.adding);
+
+                let names: Vec<String> = groups(&state.domains).into_iter().map(|g| g.0).collect();
+                let hint = if names.is_empty() {
+                    String::from("e.g. social, news, video, shopping")
+                } else {
+                    format!("Existing: {}", names.join(", "))
+                };
+                self.w_boxed(0, 2, &hint);
+                self.w_boxed(0, 3, "Leave empty for no group, enter to finish.");
+
+                self.w(0, 4, &make_bottom(self.width()));
             }
             Mode::Password => {
-                // TODO(cgag): like 95% duplication from the Mode::Insert
-                // arm...
-                self.w(0, 0, &make_label("Type the passphrase below to save"));
-                self.w_boxed(0, 1, &state.correct_pass);
+                self.draw_challenge(&state.challenge.title, None, state);
+            }
+            Mode::Unlock => {
+                let url = &state.domains[state.selected].url;
+                self.draw_challenge(&format!("Unblock {}", url),
+                                    Some(&state.challenge.title),
+                                    state);
+            }
+            Mode::Duration => {
+                let url = &state.domains[state.selected].url;
+                self.w(0, 0, &make_label(&format!("Unblock {} for", url), self.width()));
+
+                self.w_input(1, &state.adding);
+
+                self.w_boxed(0, 2, "Minutes, or hours like 2h, up to a week. Enter to finish.");
 
-                self.w(0, 2, VERTICAL_LINE);
-                self.w(2, 2, &last_n_chars(&state.pass_input, BOX_WIDTH - 5));
-                self.w(min(state.pass_input.len() + 2, BOX_WIDTH - 3), 2, "_");
-                self.w(BOX_WIDTH - 1, 2, VERTICAL_LINE);
+                self.w(0, 3, &make_bottom(self.width()));
+            }
+            Mode::Cooldown => {
+                let left = state.cooldown_until.saturating_sub(now());
+                self.w(0, 0, &make_label("Cooling down", self.width()));
+                let what = match state.gate {
+                    Mode::Unlock => "Unblocking",
+                    _ => "Saving",
+                };
+                self.w_boxed(0, 1, &format!("{} in {} seconds.", what, left));
+                self.w_boxed(0, 2, "Esc to cancel.");
+                self.w(0, 3, &make_bottom(self.width()));
+            }
+            Mode::Reason => {
+                self.w(0, 0, &make_label("Why unblock?", self.width()));
+                if waiting(state) > 0 {
+                    self.w_boxed(0, 1, &format!("Unblocking a lot lately, wait {} seconds.",
+                                                waiting(state)));
+                    self.w(0, 2, &make_bottom(self.width()));
+                    return self.present();
+                }
+
+                self.w_input(1, &state.adding);
 
-                self.w(0, 3, &make_bottom());
+                let hint = if state.reason.is_empty() {
+                    String::from("Enter to go on, esc to give up.")
+                } else {
+                    format!("Type the same reason again, {} more.", state.reasons_left)
+                };
+                self.w_boxed(0, 2, &hint);
+
+                self.w(0, 3, &make_bottom(self.width()));
             }
             Mode::Help => {
                 let mut y = 0;
-                self.w(0, y, &make_label("Help"));
+                self.w(0, y, &make_label("Help", self.width()));
                 y += 1;
 
                 let movements = vec![("j", "down"),
                                      ("k", "up"),
                                      ("J", "GOTO bottom"),
-                                     ("K", "GOTO top")];
+                                     ("K", "GOTO top"),
+                                     ("PgDn/PgUp", "page down, up"),
+                                     ("ctrl-d/ctrl-u", "half a page down, up")];
 
                 for &(movement, desc) in &movements {
                     self.w_boxed(0, y, &(String::from(movement) + " - " + desc));
@@ -640,47 +5561,28 @@ impl ScreenWriter for RustBox {
                 let controls = vec![("i", "Add a domain to block."),
                                     ("d", "Remove highlighted domain."),
                                     ("<space>", "Toggle blocked/unblocked"),
+                                    ("t", "Unblock for a few minutes"),
+                                    ("s", "Set the address a domain resolves to"),
+                                    ("a", "Put the domain in a group"),
+                                    ("g", "Groups, <space> toggles a group"),
+                                    ("/", "Filter, n/N next/previous, esc clears"),
+                                    ("m", "Mark for d, <space> and a, esc clears"),
+                                    ("v", "Pick a range for d, <space>, a or m"),
+                                    ("u", "Undo the last change"),
+                                    ("ctrl-r", "Redo what was undone"),
                                     ("q", "quit / back one screen")];
                 for &(control, desc) in &controls {
                     self.w_boxed(0, y, &(String::from(control) + " - " + desc));
                     y += 1;
                 }
 
-                self.w(0, controls.len() + 1 + movements.len() + 1, &make_bottom());
+                self.w(0, controls.len() + 1 + movements.len() + 1, &make_bottom(self.width()));
             }
         }
         self.present();
     }
 }
 
-fn gen_pass(num_words: usize) -> String {
-    let mut choices = vec!["dilate",
-                           "indict",
-                           "sacrilegious",
-                           "ingenious",
-                           "minuscule",
-                           "onomatopoeia",
-                           "accommodate",
-                           "conscientious",
-                           "Wednesday",
-                           "acquiesce",
-                           "bologna",
-                           "fuchsia",
-                           "nauseous",
-                           "orangutan",
-                           "paraphernalia",
-    ];
-
-    let mut rng = rand::thread_rng();
-    rng.shuffle(&mut choices);
-
-    choices.into_iter()
-           .take(num_words)
-           .map(|choice| choice.to_owned())
-           .collect::<Vec<String>>()
-           .join(" ")
-}
-
 fn str_repeat(s: String, n: usize) -> String {
     std::iter::repeat(s).take(n).collect::<Vec<_>>().join("")
 }
@@ -692,10 +5594,47 @@ fn truncate(s: &str, n: usize) -> String {
     if s.len() <= n {
         return String::from(s)
     }
+    // No room for the dots.
+    if n <= tail.len() {
+        return UnicodeSegmentation::graphemes(s, true).take(n).collect::<String>();
+    }
 
     UnicodeSegmentation::graphemes(s, true).take(n - tail.len()).collect::<String>() + tail
 }
 
+// Breaks text into lines of at most `width` characters at spaces, splitting
+// words that are longer than a line on their own.
+fn wrap(text: &str, width: usize) -> Vec<String> {
+    let width = max(width, 1);
+    let mut lines = vec![];
+    let mut line = String::new();
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().collect();
+        while word.len() > width {
+            if !line.is_empty() {
+                lines.push(line);
+                line = String::new();
+            }
+            lines.push(word.drain(..width).collect());
+        }
+        if word.is_empty() {
+            continue;
+        }
+        if !line.is_empty() && line.chars().count() + 1 + word.len() > width {
+            lines.push(line);
+            line = String::new();
+        }
+        if !line.is_empty() {
+            line.push(' ');
+        }
+        line.extend(word);
+    }
+    if !line.is_empty() || lines.is_empty() {
+        lines.push(line);
+    }
+    lines
+}
+
 fn last_n_chars(s: &str, n: usize) -> String {
     if s.len() <= n {
         return String::from(s)
@@ -703,3 +5642,265 @@ fn last_n_chars(s: &str, n: usize) -> String {
 
     UnicodeSegmentation::graphemes(s, true).skip(s.len() - n).collect::<String>()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(text: &str) -> String {
+        render_hosts(&parse_hosts(text))
+    }
+
+    #[test]
+    fn keeps_comments_blank_lines_and_aliases() {
+        let text = "127.0.0.1\tlocalhost\n\
+                    # The following lines are desirable for IPv6 capable hosts\n\
+                    ::1     ip6-localhost ip6-loopback\n\
+                    \n\
+                    ### HostBlock\n\
+                    ## group news schedule:09:00-17:00\n\
+                    # work stuff\n\
+                    127.0.0.1   reddit.com www.reddit.com  # group:news note to self\n\
+                    \n\
+                    #127.0.0.1\tnews.ycombinator.com\n\
+                    0.0.0.0 twitter.com\n\
+                    # the end\n\
+                    ### End HostBlock\n\
+                    10.0.0.2 printer\n";
+        let hosts = parse_hosts(text);
+        assert!(hosts.warnings.is_empty());
+        assert_eq!(hosts.domains.len(), 3);
+        assert_eq!(hosts.domains[0].aliases, vec!["www.reddit.com"]);
+        assert_eq!(hosts.domains[0].group, Some(String::from("news")));
+        assert!(hosts.domains[1].status == DomainStatus::Unblocked);
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn keeps_a_missing_trailing_newline() {
+        let text = "127.0.0.1 localhost\n### HostBlock\n127.0.0.1\tx.com\n### End HostBlock";
+        assert_eq!(round_trip(text), text);
+        assert_eq!(round_trip("127.0.0.1 localhost"), "127.0.0.1 localhost");
+    }
+
+    #[test]
+    fn keeps_a_missing_end_line() {
+        let text = "127.0.0.1 localhost\n### HostBlock\n127.0.0.1\tx.com\n# comment\n";
+        let hosts = parse_hosts(text);
+        assert_eq!(hosts.warnings.len(), 1);
+        assert_eq!(hosts.domains.len(), 1);
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn folds_ipv4_and_ipv6_pairs() {
+        let text = "### HostBlock\n\
+                    127.0.0.1\tx.com\t# group:social\n\
+                    ::1\tx.com\n\
+                    #0.0.0.0 y.com\n\
+                    #:: y.com\n\
+                    ::1 z.com\n\
+                    ### End HostBlock\n";
+        let hosts = parse_hosts(text);
+        assert_eq!(hosts.domains.len(), 3);
+        assert_eq!(hosts.domains[0].ip, Some(String::from("127.0.0.1")));
+        assert_eq!(hosts.domains[0].ip6, Some(String::from("::1")));
+        assert!(hosts.domains[1].status == DomainStatus::Unblocked);
+        assert_eq!(hosts.domains[2].ip, None);
+        assert_eq!(round_trip(text), text);
+    }
+
+    #[test]
+    fn only_rewrites_changed_entries() {
+        let text = "### HostBlock\n127.0.0.1   a.com  # keep\n127.0.0.1   b.com\n### End HostBlock\n";
+        let mut hosts = parse_hosts(text);
+        hosts.domains[1].status = DomainStatus::Unblocked;
+        assert_eq!(render_hosts(&hosts),
+                   "### HostBlock\n127.0.0.1   a.com  # keep\n#127.0.0.1\tb.com\n### End HostBlock\n");
+    }
+
+    fn minute(clock: &str) -> u32 {
+        parse_clock(clock).unwrap()
+    }
+
+    #[test]
+    fn parses_day_ranges() {
+        let f = false;
+        let t = true;
+        assert_eq!(parse_days("mon-fri").unwrap(), [t, t, t, t, t, f, f]);
+        assert_eq!(parse_days("fri-mon").unwrap(), [t, f, f, f, t, t, t]);
+        assert_eq!(parse_days("sat,sun").unwrap(), parse_days("weekends").unwrap());
+        assert_eq!(parse_days("weekdays,sun").unwrap(), [t, t, t, t, t, f, t]);
+        assert_eq!(parse_days("daily").unwrap(), [t; 7]);
+        assert!(parse_days("someday").is_err());
+        assert!(parse_days("mon-").is_err());
+    }
+
+    #[test]
+    fn rejects_bad_schedules() {
+        assert!(parse_schedule("").is_err());
+        assert!(parse_schedule("09:00").is_err());
+        assert!(parse_schedule("9-17").is_err());
+        assert!(parse_schedule("25:00-26:00").is_err());
+        assert!(parse_schedule("09:00-17:00@someday").is_err());
+        assert_eq!(parse_schedule("09:00-12:00;13:00-17:00@weekdays").unwrap().len(), 2);
+    }
+
+    #[test]
+    fn day_windows_end_before_their_end_time() {
+        let windows = parse_schedule("09:00-17:00@weekdays").unwrap();
+        assert!(!in_window(&windows, 0, minute("08:59")));
+        assert!(in_window(&windows, 0, minute("09:00")));
+        assert!(in_window(&windows, 4, minute("16:59")));
+        assert!(!in_window(&windows, 4, minute("17:00")));
+        assert!(!in_window(&windows, 5, minute("12:00")));
+    }
+
+    #[test]
+    fn windows_past_midnight_belong_to_the_day_they_start() {
+        let windows = parse_schedule("22:00-07:00@fri").unwrap();
+        assert!(!in_window(&windows, 4, minute("06:00")));
+        assert!(!in_window(&windows, 4, minute("21:59")));
+        assert!(in_window(&windows, 4, minute("22:00")));
+        assert!(in_window(&windows, 4, minute("23:59")));
+        assert!(in_window(&windows, 5, minute("00:00")));
+        assert!(in_window(&windows, 5, minute("06:59")));
+        assert!(!in_window(&windows, 5, minute("07:00")));
+        assert!(!in_window(&windows, 5, minute("23:00")));
+    }
+
+    #[test]
+    fn sunday_night_runs_into_monday() {
+        let windows = parse_schedule("23:00-01:00@fri-sun").unwrap();
+        assert!(in_window(&windows, 6, minute("23:30")));
+        assert!(in_window(&windows, 0, minute("00:30")));
+        assert!(!in_window(&windows, 0, minute("23:30")));
+        assert!(!in_window(&windows, 4, minute("00:30")));
+    }
+
+    #[test]
+    fn equal_start_and_end_is_all_day() {
+        let windows = parse_schedule("00:00-00:00@sat,sun").unwrap();
+        assert!(!in_window(&windows, 4, minute("23:59")));
+        assert!(in_window(&windows, 5, minute("00:00")));
+        assert!(in_window(&windows, 6, minute("12:00")));
+        assert!(in_window(&windows, 6, minute("23:59")));
+        assert!(!in_window(&windows, 0, minute("00:00")));
+    }
+
+    fn json(text: &str) -> Result<HashMap<String, String>, String> {
+        parse_json_object(text)
+    }
+
+    #[test]
+    fn parses_json_requests() {
+        let req = json(r#" { "cmd" : "unblock", "id": 3, "minutes":1.5, "yes":true } "#).unwrap();
+        assert_eq!(req["cmd"], "unblock");
+        assert_eq!(req["id"], "3");
+        assert_eq!(req["minutes"], "1.5");
+        assert_eq!(req["yes"], "true");
+        assert!(json("{}").unwrap().is_empty());
+        assert!(json(" { } ").unwrap().is_empty());
+    }
+
+    #[test]
+    fn unescapes_json_strings() {
+        let req = json(r#"{"a":"q\"b\\s\/n\nt\tr\rb\bf\f"}"#).unwrap();
+        assert_eq!(req["a"], "q\"b\\s/n\nt\tr\rb\u{8}f\u{c}");
+        let req = json(r#"{"a":"é中😀"}"#).unwrap();
+        assert_eq!(req["a"], "\u{e9}\u{4e2d}\u{1f600}");
+        assert!(json(r#"{"a":"\x"}"#).is_err());
+        assert!(json(r#"{"a":"\u12"}"#).is_err());
+        assert!(json(r#"{"a":"\u12g4"}"#).is_err());
+        assert!(json(r#"{"a":"\ud83d"}"#).is_err());
+        assert!(json(r#"{"a":"\ud83dA"}"#).is_err());
+        assert!(json(r#"{"a":"\ude00"}"#).is_err());
+        assert!(json(r#"{"a":"open}"#).is_err());
+    }
+
+    #[test]
+    fn leaves_out_json_nulls() {
+        let req = json(r#"{"domain":null,"group":"news"}"#).unwrap();
+        assert!(!req.contains_key("domain"));
+        assert_eq!(req["group"], "news");
+        assert!(json(r#"{"domain":nul}"#).is_err());
+    }
+
+    #[test]
+    fn rejects_malformed_json() {
+        assert!(json(r#"{"a":"b",}"#).is_err());
+        assert!(json(r#"{"a":null,}"#).is_err());
+        assert!(json(r#"{,}"#).is_err());
+        assert!(json(r#"{"a":"b"} junk"#).is_err());
+        assert!(json(r#"{"a":"b"}{}"#).is_err());
+        assert!(json(r#"{"a" "b"}"#).is_err());
+        assert!(json(r#"{"a":"b""#).is_err());
+        assert!(json(r#"{"a":[1]}"#).is_err());
+        assert!(json(r#"{"a":{}}"#).is_err());
+        assert!(json(r#"["a"]"#).is_err());
+        assert!(json("").is_err());
+    }
+
+    #[test]
+    fn parses_durations() {
+        assert_eq!(parse_duration("90"), Some(90 * 60));
+        assert_eq!(parse_duration("90m"), Some(90 * 60));
+        assert_eq!(parse_duration("2h"), Some(2 * 60 * 60));
+        assert_eq!(parse_duration("168h"), Some(MAX_UNBLOCK));
+        assert_eq!(parse_duration("169h"), None);
+        assert_eq!(parse_duration("99999999999999999h"), None);
+        assert_eq!(parse_duration("0"), None);
+        assert_eq!(parse_duration("-5m"), None);
+        assert_eq!(parse_duration("h"), None);
+    }
+
+    #[test]
+    fn arithmetic_stays_small() {
+        for size in 0..13 {
+            for _ in 0..200 {
+                let puzzle = Arithmetic.generate(size);
+                let terms = puzzle.prompt.split(&['+', '-'][..]);
+                assert!(terms.map(|t| t.matches('*').count()).all(|n| n <= 1), "{}", puzzle.prompt);
+                assert!(puzzle.answer.trim_start_matches('-').len() <= 6, "{}", puzzle.prompt);
+            }
+        }
+    }
+
+    #[test]
+    fn paragraphs_grow_with_size() {
+        let lengths: Vec<usize> = [2, 3, 4, 6, 12, 20].iter()
+                                                      .map(|&n| Paragraph.generate(n).answer.len())
+                                                      .collect();
+        assert_eq!(lengths[0], PARAGRAPH.len());
+        assert_eq!(lengths[0], lengths[1]);
+        assert!(lengths[2] > lengths[1] && lengths[3] > lengths[2] && lengths[4] > lengths[3]);
+        assert_eq!(lengths[4], lengths[5]);
+    }
+
+    fn glob(pattern: &str, text: &str) -> bool {
+        glob_match(&pattern.chars().collect::<Vec<_>>(), &text.chars().collect::<Vec<_>>())
+    }
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob("*.co.uk", "bbc.co.uk"));
+        assert!(!glob("*.co.uk", "bbc.co.uk.evil.com"));
+        assert!(glob("news.*", "news.ycombinator.com"));
+        assert!(glob("*", ""));
+        assert!(glob("", ""));
+        assert!(!glob("", "a"));
+        assert!(glob("?.com", "x.com"));
+        assert!(!glob("?.com", ".com"));
+        assert!(glob("*a*b", "xaxxab"));
+        assert!(glob("a**b", "ab"));
+        assert!(!glob("*a*b", "xaxxa"));
+        assert!(glob("*red*it*", "old.reddit.com"));
+    }
+
+    #[test]
+    fn globs_dont_backtrack_forever() {
+        let text: Vec<char> = "a".repeat(5000).chars().collect();
+        let pattern: Vec<char> = "*a*a*a*a*a*a*a*a*b".chars().collect();
+        assert!(!glob_match(&pattern, &text));
+    }
+}